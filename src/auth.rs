@@ -1,9 +1,14 @@
 //! Authentication and unauthentication.
+//!
+//! Passphrases and authentication tokens are held in [`Secret`]s, which are
+//! wiped when dropped and are redacted when debug formatted.
+
+pub mod mech;
+mod secret;
 
 use std::borrow::Borrow;
-use std::fs::File;
-use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
 
 use tokio::io::{AsyncRead, AsyncWrite};
 
@@ -11,11 +16,16 @@ use tokio_util::codec::Framed;
 
 use serde::Deserialize;
 
-use blather::Telegram;
+use zeroize::Zeroize;
+
+use blather::{Params, Telegram};
 
+use crate::conn::Codec;
 use crate::utils;
 use crate::Error;
 
+pub use secret::Secret;
+
 
 /// Authentication context used to signal how to authenticate a connection.
 #[derive(Clone, Debug, Default, Deserialize)]
@@ -23,93 +33,133 @@ pub struct Auth {
   /// Account name used to authenticate.
   pub name: Option<String>,
 
-  /// Load raw account passphrase from the specified filename.
+  /// Load raw account passphrase from the specified filename.  See
+  /// [`CredStore::File`] for how the file is read.
   #[serde(rename = "pass-file")]
   pub pass_file: Option<String>,
 
   /// Raw account passphrase to authenticate with.  Only used if `name` has
   /// been set.
-  pub pass: Option<String>,
+  pub pass: Option<Secret>,
+
+  /// Load raw account passphrase from the specified environment variable.
+  #[serde(rename = "pass-env")]
+  pub pass_env: Option<String>,
+
+  /// Run the specified command, and read the raw account passphrase from its
+  /// standard output.  The first element is the program to run and the rest
+  /// are its arguments.
+  #[serde(rename = "pass-cmd")]
+  pub pass_cmd: Option<Vec<String>>,
 
   /// Use the specified file for authentication token storage.
+  ///
+  /// Newly issued tokens replace the file's contents atomically, and the file
+  /// is made readable only by its owner.  Writers serialize using an advisory
+  /// lock on a `.lock` file next to it.
   #[serde(rename = "token-file")]
   pub token_file: Option<String>,
 
   /// Authentication token.
-  pub token: Option<String>
+  pub token: Option<Secret>,
+
+  /// Load authentication token from the specified environment variable.
+  #[serde(rename = "token-env")]
+  pub token_env: Option<String>,
+
+  /// Run the specified command, and read the authentication token from its
+  /// standard output.
+  #[serde(rename = "token-cmd")]
+  pub token_cmd: Option<Vec<String>>
 }
 
 
 impl Auth {
-  /// Return `true` if there's either a raw passphrase set in `pass` or a
-  /// passphrase file has beeen set in `pass_file`.  This function does not
-  /// validate if the passphrase file exists or is accessible.
+  /// Return `true` if a passphrase source has been set in `pass`,
+  /// `pass_file`, `pass_env` or `pass_cmd`.  This function does not validate
+  /// that the passphrase can actually be loaded.
   pub fn have_pass(&self) -> bool {
-    self.pass.is_some() || self.pass_file.is_some()
+    self.pass_store().is_some()
   }
 
-  /// Get passphrase.
-  ///
-  /// Return the raw `pass` field if set.  Otherwise, load the `pass_file` if
-  /// set, and return error if the passphrase could not be loaded from the
-  /// file.
-  ///
-  /// If neither `pass` nor `pass_file` have been set, return an error.
-  pub fn get_pass(&self) -> Result<String, Error> {
-    // 1. Return raw passphrase if set
-    // 2. Load raw passphrase from file, if set.  Return error if file could
-    //    not be read.
-    // 3. Return `Ok(None)`
+  /// Return the passphrase source which takes precedence.
+  fn pass_store(&self) -> Option<CredStore> {
     if let Some(pass) = &self.pass {
-      Ok(pass.clone())
+      Some(CredStore::Buf(pass.clone()))
     } else if let Some(fname) = &self.pass_file {
-      if let Some(pass) = utils::read_single_line(fname) {
-        Ok(pass)
-      } else {
-        return Err(Error::invalid_cred(
-          "Unable to read passphrase from file"
-        ));
-      }
+      Some(CredStore::File(PathBuf::from(fname)))
+    } else if let Some(var) = &self.pass_env {
+      Some(CredStore::Env(var.clone()))
     } else {
-      Err(Error::invalid_cred("Missing passphrase"))
+      self
+        .pass_cmd
+        .as_ref()
+        .map(|argv| CredStore::Command(argv.clone()))
+    }
+  }
+
+  /// Get passphrase.
+  ///
+  /// Return the raw `pass` field if set.  Otherwise, load it from the first
+  /// of `pass_file`, `pass_env` and `pass_cmd` which has been set, and return
+  /// error if the passphrase could not be loaded from it.
+  ///
+  /// If no passphrase source has been set, return an error.
+  pub async fn get_pass(&self) -> Result<Secret, Error> {
+    match self.pass_store() {
+      Some(store) => store.load().await,
+      None => Err(Error::invalid_cred("Missing passphrase"))
     }
   }
 
   /// Get authentication token.
   ///
   /// Return the raw `token` field if set.  Otherwise, check if `token_file` is
-  /// set.  If it is, then attempt to load the token from it.  If the file
-  /// _does not_ exist, then:
+  /// set.  If it is, and the file exists, then attempt to load the token from
+  /// it.  Otherwise load the token from `token_env` or `token_cmd` if either
+  /// is set.  If `token_file` is set but _does not_ exist, then:
   /// - Return `Ok(None)` if account name and pass(file) have been set.
   /// - Return error if account name has not been set.
-  pub fn get_token(&self) -> Result<Option<String>, Error> {
+  pub async fn get_token(&self) -> Result<Option<Secret>, Error> {
     // 1. Return raw token if set.
-    // 2. If a token file has been specified, then:
-    //    - If file exists, then read token from it.
-    //    - If file does not exist, then:
-    //      - If username is set and pass(file) is set, then return `Ok(None)`,
-    //        assuming the caller wants to request a token and store it in the
-    //        specified file.
-    //      - If neither username nor pass(file) is set, then return an error,
-    //        since the token file is missing.
+    // 2. If a token file has been specified, and it exists, then read token
+    //    from it.
+    // 3. Load token from the environment or a helper command if either has
+    //    been specified.
+    // 4. If a token file has been specified, but it does not exist, then:
+    //    - If username is set and pass(file) is set, then return `Ok(None)`,
+    //      assuming the caller wants to request a token and store it in the
+    //      specified file.
+    //    - If neither username nor pass(file) is set, then return an error,
+    //      since the token file is missing.
     if let Some(tkn) = &self.token {
-      Ok(Some(tkn.clone()))
-    } else if let Some(fname) = &self.token_file {
+      return Ok(Some(tkn.clone()));
+    }
+
+    if let Some(fname) = &self.token_file {
+      // If it can't be determined whether the file exists, then let the
+      // attempt to load it report why.
       let fname = Path::new(&fname);
-      if fname.exists() {
-        // Token file exists, attempt to load token from it
-        if let Some(tkn) = utils::read_single_line(fname) {
-          // Got it, hopefully.  The server will validate it.
-          Ok(Some(tkn))
-        } else {
-          // Unable to read file.
-          Err(Error::invalid_cred("Unable to read token from file"))
-        }
-      } else if self.name.is_none() {
+      if tokio::fs::try_exists(fname).await.unwrap_or(true) {
+        // Token file exists, attempt to load token from it.  The server will
+        // validate it.
+        return read_file(fname).await.map(Some);
+      }
+    }
+
+    if let Some(var) = &self.token_env {
+      return CredStore::Env(var.clone()).load().await.map(Some);
+    }
+    if let Some(argv) = &self.token_cmd {
+      return CredStore::Command(argv.clone()).load().await.map(Some);
+    }
+
+    if self.token_file.is_some() {
+      if self.name.is_none() {
         // Missing account name, so clearly the authentication call won't be
         // able to request a token to be stored in the non-existent file.
         Err(Error::invalid_cred("Unable to read token from file"))
-      } else if self.pass.is_none() && self.pass_file.is_none() {
+      } else if !self.have_pass() {
         // Have account name, but no passphrase, so authentication can't
         // succeed.
         Err(Error::invalid_cred("Missing passphrase for token request"))
@@ -132,25 +182,45 @@ impl Auth {
   /// stored in the `Auth` buffer using the following logic:
   ///
   /// 1. If a raw token has been supplied in the `token` field, then attempt
-  ///    to authenticate with it and return the results.
+  ///    to authenticate with it.  If the server accepts it, return
+  ///    [`AuthOutcome::Token`].
   /// 2. If a `token_file` has been been set, then:
-  ///    - If the file exists, try to load the authentication token,
-  ///      authenticate with it, and return the results.
-  ///    - If the file does not exist, then:
-  ///      - If account name and/or passphrase have not been set, then return
-  ///        error.
-  ///      - If account name and passphrase have been set, then continue.
-  /// 3. Make sure that an account name and a passphrase has been set.
-  ///    The passphrase is either set from the `pass` field or by loading the
-  ///    contents of the file in `pass_file`.  Return error account name or
+  ///    - If the file exists, try to load the authentication token and
+  ///      authenticate with it.  If the server accepts it, return
+  ///      [`AuthOutcome::Token`].
+  ///    - If the file does not exist, then continue.
+  /// 3. If `token_env` or `token_cmd` has been set, then load the
+  ///    authentication token from it and authenticate with it.  If the
+  ///    server accepts it, return [`AuthOutcome::Token`].  Otherwise, if
+  ///    `token_file` has been set but does not exist, then return error if
+  ///    account name and/or passphrase have not been set.
+  /// 4. Make sure that an account name and a passphrase has been set.
+  ///    The passphrase is either set from the `pass` field or loaded from
+  ///    `pass_file`, `pass_env` or `pass_cmd`.  Return error account name or
   ///    passphrase can not be acquired.
-  /// 4. Authenticate using account name and passphrase.  If a `token_file` was
+  /// 5. Authenticate using account name and passphrase.  If a `token_file` was
   ///    specified, then request an authentication token and store it in
-  ///    `token_file` on success.  Return error on failure.
+  ///    `token_file` on success.  Return [`AuthOutcome::AccPass`] on success
+  ///    and error on failure.
+  ///
+  /// If the server rejects the token in steps 1 to 3 (for instance because
+  /// it has expired or been revoked), and an account name and passphrase
+  /// have been set, then authentication falls back to step 5.  A new token
+  /// is always requested in this case, and it replaces the stale one in
+  /// `token_file` if one has been set.  [`AuthOutcome::Fallback`] is
+  /// returned if this succeeds.  The reason for the rejection is not
+  /// inspected; any `Fail` reply to the token is treated as a rejection and
+  /// passed on in [`AuthOutcome::Fallback::reason`].
+  ///
+  /// Failing to write the new token to `token_file` does not fail the
+  /// authentication, since the connection has already been authenticated by
+  /// then.  A warning is logged, and the token is still returned in the
+  /// outcome so the caller can store it elsewhere.
+  #[tracing::instrument(level = "debug", skip_all, fields(name = ?self.name))]
   pub async fn authenticate<C>(
     &self,
-    conn: &mut Framed<C, blather::Codec>
-  ) -> Result<Option<String>, Error>
+    conn: &mut Framed<C, Codec>
+  ) -> Result<AuthOutcome, Error>
   where
     C: AsyncRead + AsyncWrite + Unpin
   {
@@ -158,46 +228,71 @@ impl Auth {
     // This will return Ok(None) if there's no token to be added to the `Auth`
     // server request, but it can also mean that the caller wants a token to be
     // *requested* (the `token_file` field needs to be checked for this).
-    let tkn = self.get_token()?;
+    let tkn = self.get_token().await?;
 
-    if let Some(tkn) = tkn {
+    let rejected = if let Some(tkn) = tkn {
       // Authenticate using the token
-      token(conn, CredStore::Buf(tkn)).await?;
-
-      // Token authentications do not yield new tokens
-      return Ok(None);
-    }
+      match token(conn, CredStore::Buf(tkn)).await {
+        // Token authentications do not yield new tokens
+        Ok(()) => return Ok(AuthOutcome::Token),
+        // The server does not say why it failed the request, so any failure
+        // is taken to mean that the token was rejected.
+        Err(Error::ServerError(params))
+          if self.name.is_some() && self.have_pass() =>
+        {
+          tracing::info!(
+            "token rejected; falling back to account name and passphrase"
+          );
+          Some(params)
+        }
+        Err(e) => return Err(e)
+      }
+    } else {
+      None
+    };
 
     // If a token authentication wasn't performed, then require an account.
     if let Some(accname) = &self.name {
       // Get required passphrase.  (Note that this will return error if a
-      // password can't be retrieved).
-      let pass = self.get_pass()?;
+      // passphrase can't be retrieved).
+      let pass = self.get_pass().await?;
 
       // Authenticate using account name and passphrase.  If a token file has
       // been set, then at this point it is safe to assume the caller wants to
-      // request a token.
+      // request a token.  When replacing a rejected token, always request a
+      // new one.
       let opttkn = accpass(
         conn,
         accname,
         CredStore::Buf(pass),
-        self.token_file.is_some()
+        self.token_file.is_some() || rejected.is_some()
       )
       .await?;
 
       // If a token was returned, and a token file was specified, then attempt
-      // to write the token to the file.
-      if let Some(tkn) = opttkn {
-        // (token_file should always be Some() if an opttkn was returned)
+      // to write the token to the file.  The file is replaced atomically and
+      // is only readable by its owner.
+      if let Some(tkn) = &opttkn {
         if let Some(fname) = &self.token_file {
-          let mut f = File::create(fname)?;
-          f.write(tkn.as_bytes())?;
+          if let Err(e) =
+            utils::write_secret_file(fname, tkn.expose().as_bytes()).await
+          {
+            tracing::warn!(
+              "unable to write authentication token to '{}'; {}",
+              fname,
+              e
+            );
+          }
         }
-        return Ok(Some(tkn));
       }
 
-      // Successfully authenticated using user name and passphrase
-      return Ok(None);
+      return Ok(match rejected {
+        Some(reason) => AuthOutcome::Fallback {
+          token: opttkn,
+          reason
+        },
+        None => AuthOutcome::AccPass { token: opttkn }
+      });
     }
 
     // Token authetication failed and no account name/password was passed, so
@@ -206,41 +301,196 @@ impl Auth {
   }
 }
 
+
+/// How [`Auth::authenticate()`] authenticated a connection.
+#[derive(Clone, Debug)]
+pub enum AuthOutcome {
+  /// The connection was authenticated using an authentication token.
+  Token,
+
+  /// The connection was authenticated using an account name and passphrase.
+  /// `token` holds the new authentication token, if one was requested.
+  AccPass { token: Option<Secret> },
+
+  /// The authentication token was rejected by the server, and the connection
+  /// was instead authenticated using an account name and passphrase.
+  /// `token` holds the replacement authentication token, and `reason` the
+  /// parameters of the server's rejection of the old one.
+  Fallback {
+    token: Option<Secret>,
+    reason: blather::Params
+  }
+}
+
+impl AuthOutcome {
+  /// Return the authentication token that was issued, if any.
+  pub fn token(&self) -> Option<&Secret> {
+    match self {
+      AuthOutcome::Token => None,
+      AuthOutcome::AccPass { token } | AuthOutcome::Fallback { token, .. } => {
+        token.as_ref()
+      }
+    }
+  }
+}
+
 /// Choose where an a token/passphrase is fetched from.
+#[derive(Debug)]
 pub enum CredStore {
-  /// Credential is stored in a string.
-  Buf(String),
+  /// Credential is stored in a buffer.
+  Buf(Secret),
+
+  /// Credential is stored in the first line of a file.
+  ///
+  /// The line is used exactly as it is, excluding its line terminator.  On
+  /// unix, loading fails if the file can be read by others, and a warning is
+  /// logged if it can be read by its group.
+  File(PathBuf),
+
+  /// Credential is stored in an environment variable.
+  Env(String),
+
+  /// Credential is written to standard output by a helper program.  The
+  /// first element is the program to run and the rest are its arguments.
+  ///
+  /// The program's standard error is inherited, so it can prompt the user.
+  /// The first line of its output is used, excluding its line terminator.
+  /// The program must exit successfully.
+  Command(Vec<String>)
+}
+
+impl CredStore {
+  /// Fetch the credential.
+  pub async fn load(&self) -> Result<Secret, Error> {
+    match self {
+      CredStore::Buf(s) => Ok(s.clone()),
+      CredStore::File(p) => read_file(p).await,
+      CredStore::Env(var) => match std::env::var(var) {
+        Ok(s) => Ok(Secret::from(s)),
+        Err(_) => Err(Error::invalid_cred(&format!(
+          "Environment variable '{}' is not set",
+          var
+        )))
+      },
+      CredStore::Command(argv) => run_helper(argv).await
+    }
+  }
+}
+
+
+/// Load a credential from a file.
+async fn read_file(fname: &Path) -> Result<Secret, Error> {
+  let what = format!("'{}'", fname.display());
+  let io_err = |e: std::io::Error| {
+    let msg = match e.kind() {
+      std::io::ErrorKind::NotFound => format!("{} does not exist", what),
+      std::io::ErrorKind::PermissionDenied => {
+        format!("Permission denied reading {}", what)
+      }
+      _ => format!("Unable to read {}; {}", what, e)
+    };
+    Error::invalid_cred(&msg)
+  };
 
-  /// Credential is stored in a file.
-  File(PathBuf)
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = tokio::fs::metadata(fname)
+      .await
+      .map_err(io_err)?
+      .permissions()
+      .mode();
+    if mode & 0o007 != 0 {
+      return Err(Error::invalid_cred(&format!(
+        "{} is accessible by others (mode {:o})",
+        what,
+        mode & 0o777
+      )));
+    }
+    if mode & 0o070 != 0 {
+      tracing::warn!(
+        "{} is accessible by its group (mode {:o})",
+        what,
+        mode & 0o777
+      );
+    }
+  }
+
+  let buf = tokio::fs::read(fname).await.map_err(io_err)?;
+  first_line(buf, &what)
+}
+
+
+/// Return the first line of `buf`, excluding its line terminator, as a
+/// secret.  `buf` is wiped.
+fn first_line(buf: Vec<u8>, what: &str) -> Result<Secret, Error> {
+  let raw = match String::from_utf8(buf) {
+    Ok(s) => Secret::from(s),
+    Err(e) => {
+      let mut bytes = e.into_bytes();
+      bytes.zeroize();
+      return Err(Error::invalid_cred(&format!(
+        "{} is not valid UTF-8",
+        what
+      )));
+    }
+  };
+
+  let s = raw.expose();
+  let s = s.split('\n').next().unwrap_or(s);
+  let s = s.strip_suffix('\r').unwrap_or(s);
+  if s.is_empty() {
+    return Err(Error::invalid_cred(&format!("{} is empty", what)));
+  }
+  Ok(Secret::from(s))
+}
+
+
+/// Run a credential helper and return the first line of its standard output.
+async fn run_helper(argv: &[String]) -> Result<Secret, Error> {
+  let (prog, args) = match argv.split_first() {
+    Some(a) => a,
+    None => return Err(Error::invalid_cred("Empty credential command"))
+  };
+
+  tracing::debug!(prog = %prog, "running credential helper");
+  let out = tokio::process::Command::new(prog)
+    .args(args)
+    .stdin(Stdio::null())
+    .stderr(Stdio::inherit())
+    .output()
+    .await
+    .map_err(|e| {
+      Error::invalid_cred(&format!("Unable to run '{}'; {}", prog, e))
+    })?;
+
+  // Extract the secret first, so the output is wiped however this returns.
+  let what = format!("Output of '{}'", prog);
+  let res = first_line(out.stdout, &what);
+  if !out.status.success() {
+    return Err(Error::invalid_cred(&format!(
+      "'{}' failed; {}",
+      prog, out.status
+    )));
+  }
+  res
 }
 
 
 /// Attempt to authenticate using an authentication token.
 ///
-/// The token can be stored in either a string buffer or file.
+/// The token can be loaded from any [`CredStore`].
+#[tracing::instrument(level = "debug", skip_all)]
 pub async fn token<T, O>(
-  conn: &mut Framed<T, blather::Codec>,
+  conn: &mut Framed<T, Codec>,
   tkn: O
 ) -> Result<(), Error>
 where
   O: Borrow<CredStore>,
   T: AsyncRead + AsyncWrite + Unpin
 {
-  let tkn = match tkn.borrow() {
-    CredStore::Buf(s) => s.clone(),
-    CredStore::File(p) => {
-      if let Some(t) = utils::read_single_line(p) {
-        t
-      } else {
-        return Err(Error::invalid_cred("Unable to read token from file"));
-      }
-    }
-  };
-
-  let mut tg = Telegram::new_topic("Auth")?;
-  tg.add_param("Tkn", tkn)?;
-  crate::sendrecv(conn, &tg).await?;
+  let tkn = tkn.borrow().load().await?;
+  mech::run(conn, &mut mech::Token { tkn }).await?;
   Ok(())
 }
 
@@ -250,56 +500,48 @@ where
 /// Optionally request an authentication token.
 ///
 /// On success, return `Ok(None)` if authentication token was not requested.
-/// Return `Ok(Some(String))` with the token string if it was requested.
+/// Return `Ok(Some(Secret))` with the token if it was requested.
+#[tracing::instrument(
+  level = "debug",
+  skip_all,
+  fields(accname = accname.as_ref(), reqtkn = reqtkn)
+)]
 pub async fn accpass<T, A, P>(
-  conn: &mut Framed<T, blather::Codec>,
+  conn: &mut Framed<T, Codec>,
   accname: A,
   pass: P,
   reqtkn: bool
-) -> Result<Option<String>, Error>
+) -> Result<Option<Secret>, Error>
 where
   A: AsRef<str>,
   P: Borrow<CredStore>,
   T: AsyncRead + AsyncWrite + Unpin
 {
-  let mut tg = Telegram::new_topic("Auth")?;
-  tg.add_param("AccName", accname.as_ref())?;
-
-  let pass = match pass.borrow() {
-    CredStore::Buf(s) => s.clone(),
-    CredStore::File(p) => {
-      if let Some(pass) = utils::read_single_line(p) {
-        pass
-      } else {
-        return Err(Error::invalid_cred(
-          "Unable to read passphrase from file"
-        ));
-      }
-    }
+  let mut mech = mech::AccPass {
+    name: accname.as_ref().to_string(),
+    pass: pass.borrow().load().await?,
+    reqtkn
   };
-  tg.add_param("Pass", pass)?;
+  let params = mech::run(conn, &mut mech).await?;
+  Ok(issued_token(&params, reqtkn))
+}
 
-  if reqtkn {
-    tg.add_param("ReqTkn", "True")?;
-  }
-  let params = crate::sendrecv(conn, &tg).await?;
 
+/// Extract the authentication token from the reply to a successful `Auth`
+/// request, if one was requested.
+fn issued_token(params: &Params, reqtkn: bool) -> Option<Secret> {
   if reqtkn {
-    let s = params.get_str("Tkn");
-    if let Some(s) = s {
-      Ok(Some(s.to_string()))
-    } else {
-      Ok(None)
-    }
+    params.get_str("Tkn").map(Secret::from)
   } else {
-    Ok(None)
+    None
   }
 }
 
 
 /// Return ownership of a connection to the built-in _unauthenticated_ account.
+#[tracing::instrument(level = "debug", skip_all)]
 pub async fn unauthenticate<T: AsyncRead + AsyncWrite + Unpin>(
-  conn: &mut Framed<T, blather::Codec>
+  conn: &mut Framed<T, Codec>
 ) -> Result<(), Error> {
   let tg = Telegram::new_topic("Unauth")?;
 
@@ -308,4 +550,140 @@ pub async fn unauthenticate<T: AsyncRead + AsyncWrite + Unpin>(
   Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::conn;
+  use crate::testing::MockServer;
+
+  #[tokio::test]
+  async fn fallback_on_rejected_token() {
+    let fname = std::env::temp_dir()
+      .join(format!("ddmw-token-{}.txt", std::process::id()));
+
+    let srv = MockServer::tcp().await.unwrap();
+    let id = srv.add_account("alice", "secret", &[]);
+    let stale = srv.issue_token(id);
+    srv.revoke_token(&stale);
+    utils::write_secret_file(&fname, stale.as_bytes())
+      .await
+      .unwrap();
+
+    let auth = Auth {
+      name: Some("alice".into()),
+      pass: Some("secret".into()),
+      token_file: Some(fname.to_string_lossy().into_owned()),
+      ..Auth::default()
+    };
+
+    // The revoked token is replaced using the passphrase.
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    let outcome = auth.authenticate(&mut conn).await.unwrap();
+    assert!(matches!(outcome, AuthOutcome::Fallback { .. }));
+    let tkn = outcome.token().unwrap().expose().to_string();
+    assert_ne!(tkn, stale);
+    assert_eq!(std::fs::read_to_string(&fname).unwrap(), tkn);
+
+    // The new token is used the next time around.
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    let outcome = auth.authenticate(&mut conn).await.unwrap();
+    assert!(matches!(outcome, AuthOutcome::Token));
+
+    // Without a passphrase the rejection is reported.
+    srv.revoke_token(&tkn);
+    let auth = Auth { pass: None, ..auth };
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    let res = auth.authenticate(&mut conn).await;
+    assert!(matches!(res, Err(Error::ServerError(_))));
+
+    let _ = std::fs::remove_file(&fname);
+    let mut lock = fname.into_os_string();
+    lock.push(".lock");
+    let _ = std::fs::remove_file(lock);
+  }
+
+  #[tokio::test]
+  async fn unwritable_token_file() {
+    let srv = MockServer::tcp().await.unwrap();
+    srv.add_account("alice", "secret", &[]);
+
+    let fname = std::env::temp_dir()
+      .join(format!("ddmw-nodir-{}", std::process::id()))
+      .join("token.txt");
+    let auth = Auth {
+      name: Some("alice".into()),
+      pass: Some("secret".into()),
+      token_file: Some(fname.to_string_lossy().into_owned()),
+      ..Auth::default()
+    };
+
+    // The connection is authenticated, and the token handed to the caller,
+    // even though it could not be stored.
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    let outcome = auth.authenticate(&mut conn).await.unwrap();
+    assert!(matches!(outcome, AuthOutcome::AccPass { token: Some(_) }));
+    assert!(!fname.exists());
+  }
+
+  #[tokio::test]
+  async fn cred_files() {
+    let fname = std::env::temp_dir()
+      .join(format!("ddmw-pass-{}.txt", std::process::id()));
+    let store = CredStore::File(fname.clone());
+
+    let err = store.load().await.unwrap_err().to_string();
+    assert!(err.contains("does not exist"), "{}", err);
+
+    utils::write_secret_file(&fname, b"  spaced pass  \r\nignored\n")
+      .await
+      .unwrap();
+    assert_eq!(store.load().await.unwrap().expose(), "  spaced pass  ");
+
+    utils::write_secret_file(&fname, b"\n").await.unwrap();
+    let err = store.load().await.unwrap_err().to_string();
+    assert!(err.contains("is empty"), "{}", err);
+
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      utils::write_secret_file(&fname, b"secret").await.unwrap();
+      let perms = std::fs::Permissions::from_mode(0o644);
+      std::fs::set_permissions(&fname, perms).unwrap();
+      let err = store.load().await.unwrap_err().to_string();
+      assert!(err.contains("accessible by others"), "{}", err);
+    }
+
+    let _ = std::fs::remove_file(&fname);
+    let mut lock = fname.into_os_string();
+    lock.push(".lock");
+    let _ = std::fs::remove_file(lock);
+  }
+
+  #[tokio::test]
+  async fn cred_sources() {
+    let var = format!("DDMW_TEST_PASS_{}", std::process::id());
+    std::env::set_var(&var, "from env ");
+    let s = CredStore::Env(var.clone()).load().await.unwrap();
+    assert_eq!(s.expose(), "from env ");
+    std::env::remove_var(&var);
+    assert!(CredStore::Env(var).load().await.is_err());
+
+    #[cfg(unix)]
+    {
+      let cmd =
+        |s: &str| CredStore::Command(vec!["sh".into(), "-c".into(), s.into()]);
+      let s = cmd("printf 'from cmd \\n'").load().await.unwrap();
+      assert_eq!(s.expose(), "from cmd ");
+      assert!(cmd("echo nope; exit 1").load().await.is_err());
+      assert!(cmd("true").load().await.is_err());
+    }
+    assert!(matches!(
+      CredStore::Command(Vec::new()).load().await,
+      Err(Error::InvalidCredentials(_))
+    ));
+  }
+}
+
 // vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,104 @@
+//! Authentication mechanisms.
+//!
+//! An authentication mechanism decides what the `Auth` requests sent to the
+//! server look like.  A mechanism may require several round trips; each
+//! reply from the server is passed back to the mechanism, which either
+//! returns the next request or signals that authentication is complete.
+//!
+//! The built-in mechanisms are:
+//! - [`Token`], which authenticates using an authentication token.
+//! - [`AccPass`], which sends an account name and a passphrase.
+//!
+//! Additional mechanisms can be implemented using the [`Mechanism`] trait and
+//! be run using [`run()`].
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use tokio_util::codec::Framed;
+
+use blather::{Params, Telegram};
+
+use super::Secret;
+use crate::conn::Codec;
+use crate::Error;
+
+
+/// An authentication mechanism.
+pub trait Mechanism {
+  /// Return the first `Auth` request.
+  fn start(&mut self) -> Result<Telegram, Error>;
+
+  /// Process the server's successful reply to the previous request.  Return
+  /// the next request, or `None` if authentication is complete.
+  fn step(&mut self, reply: &Params) -> Result<Option<Telegram>, Error>;
+}
+
+
+/// Authenticate the connection `conn` using the mechanism `mech`.
+///
+/// Returns the server's reply to the final request.
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn run<T, M>(
+  conn: &mut Framed<T, Codec>,
+  mech: &mut M
+) -> Result<Params, Error>
+where
+  T: AsyncRead + AsyncWrite + Unpin,
+  M: Mechanism + ?Sized
+{
+  let mut tg = mech.start()?;
+  loop {
+    let reply = crate::sendrecv(conn, &tg).await?;
+    match mech.step(&reply)? {
+      Some(next) => tg = next,
+      None => return Ok(reply)
+    }
+  }
+}
+
+
+/// Authenticate using an authentication token.
+pub struct Token {
+  pub tkn: Secret
+}
+
+impl Mechanism for Token {
+  fn start(&mut self) -> Result<Telegram, Error> {
+    let mut tg = Telegram::new_topic("Auth")?;
+    tg.add_param("Tkn", self.tkn.expose())?;
+    Ok(tg)
+  }
+
+  fn step(&mut self, _reply: &Params) -> Result<Option<Telegram>, Error> {
+    Ok(None)
+  }
+}
+
+
+/// Authenticate using an account name and a passphrase, which is sent to the
+/// server as-is.
+pub struct AccPass {
+  pub name: String,
+  pub pass: Secret,
+
+  /// Request an authentication token.
+  pub reqtkn: bool
+}
+
+impl Mechanism for AccPass {
+  fn start(&mut self) -> Result<Telegram, Error> {
+    let mut tg = Telegram::new_topic("Auth")?;
+    tg.add_param("AccName", &self.name)?;
+    tg.add_param("Pass", self.pass.expose())?;
+    if self.reqtkn {
+      tg.add_param("ReqTkn", "True")?;
+    }
+    Ok(tg)
+  }
+
+  fn step(&mut self, _reply: &Params) -> Result<Option<Telegram>, Error> {
+    Ok(None)
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
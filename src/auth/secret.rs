@@ -0,0 +1,82 @@
+//! Credential container.
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer};
+
+use zeroize::Zeroize;
+
+
+/// A passphrase, authentication token or other credential.
+///
+/// The contents are wiped from memory when a `Secret` is dropped, and its
+/// `Debug` implementation does not reveal them, so a `Secret` can safely be
+/// part of structures which are logged.  The contents are only accessible
+/// through [`expose()`](Self::expose).
+#[derive(Clone, Default)]
+pub struct Secret(String);
+
+impl Secret {
+  /// Wrap the credential `s`.
+  pub fn new<S: Into<String>>(s: S) -> Self {
+    Secret(s.into())
+  }
+
+  /// Return the credential.
+  pub fn expose(&self) -> &str {
+    &self.0
+  }
+
+  /// Returns `true` if the credential is an empty string.
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+}
+
+impl Drop for Secret {
+  fn drop(&mut self) {
+    self.0.zeroize();
+  }
+}
+
+impl fmt::Debug for Secret {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("Secret(***)")
+  }
+}
+
+impl From<String> for Secret {
+  fn from(s: String) -> Self {
+    Secret(s)
+  }
+}
+
+impl From<&str> for Secret {
+  fn from(s: &str) -> Self {
+    Secret(s.to_string())
+  }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>
+  {
+    String::deserialize(deserializer).map(Secret)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn redacted() {
+    let s = Secret::from("hunter2");
+    assert_eq!(s.expose(), "hunter2");
+    assert_eq!(format!("{:?}", Some(s)), "Some(Secret(***))");
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
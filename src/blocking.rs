@@ -0,0 +1,304 @@
+//! Synchronous API.
+//!
+//! The [`Client`] in this module wraps an asynchronous [`crate::Client`]
+//! together with a private single-threaded tokio runtime, and exposes blocking
+//! versions of its methods.  This allows simple synchronous programs to talk
+//! to DDMW without having to set up a runtime or use `async` themselves.
+//!
+//! The functions in this module must not be called from within an
+//! asynchronous context, since blocking on a runtime from within another
+//! runtime will panic.
+//!
+//! This module is only available if the `blocking` feature has been enabled.
+//!
+//! # Example
+//! ```no_run
+//! use ddmw_client::{blocking::Client, conn::ProtAddr};
+//!
+//! let pa: ProtAddr = "127.0.0.1:8777".parse().unwrap();
+//! let mut client = Client::connect(pa, None).unwrap();
+//! for ent in client.lsacc(false).unwrap() {
+//!   println!("{} {}", ent.id, ent.name);
+//! }
+//! ```
+
+use std::borrow::Borrow;
+use std::sync::Arc;
+
+use tokio::runtime::{self, Runtime};
+
+use blather::{Params, Telegram};
+
+use crate::auth::{Auth, AuthOutcome};
+use crate::conf::Config;
+use crate::conn::{stats::Stats, Options, ProtAddr, Timeouts, WhoAmI};
+use crate::err::Error;
+use crate::mgmt::acc;
+use crate::msg::{
+  recv::{self, StoreType},
+  send
+};
+use crate::probe::NodeInfo;
+use crate::types::ObjRef;
+
+
+fn runtime() -> Result<Runtime, Error> {
+  Ok(
+    runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()?
+  )
+}
+
+
+/// A blocking connection to one of a DDMW core server's client interfaces.
+///
+/// See [`crate::Client`] for details on each method.
+pub struct Client {
+  inner: crate::Client,
+  rt: Runtime
+}
+
+impl Client {
+  /// Connect to the client interface at `pa`, and authenticate using `auth`
+  /// if it has `Some` value.
+  pub fn connect(pa: ProtAddr, auth: Option<Auth>) -> Result<Self, Error> {
+    Self::connect_with(pa, auth, Options::default())
+  }
+
+  /// Same as [`connect()`](Self::connect), but allows connection options to
+  /// be specified.
+  pub fn connect_with(
+    pa: ProtAddr,
+    auth: Option<Auth>,
+    opts: Options
+  ) -> Result<Self, Error> {
+    let rt = runtime()?;
+    let inner = rt.block_on(crate::Client::connect_with(pa, auth, opts))?;
+    Ok(Client { inner, rt })
+  }
+
+  /// Connect to the sender node's message interface.
+  pub fn sender_msgif(conf: &Config) -> Result<Self, Error> {
+    let rt = runtime()?;
+    let inner = rt.block_on(crate::Client::sender_msgif(conf))?;
+    Ok(Client { inner, rt })
+  }
+
+  /// Connect to the sender node's management interface.
+  pub fn sender_mgmtif(conf: &Config) -> Result<Self, Error> {
+    let rt = runtime()?;
+    let inner = rt.block_on(crate::Client::sender_mgmtif(conf))?;
+    Ok(Client { inner, rt })
+  }
+
+  /// Connect to the receiver node's subscription interface.
+  pub fn receiver_subif(conf: &Config) -> Result<Self, Error> {
+    let rt = runtime()?;
+    let inner = rt.block_on(crate::Client::receiver_subif(conf))?;
+    Ok(Client { inner, rt })
+  }
+
+  /// Connect to the receiver node's management interface.
+  pub fn receiver_mgmtif(conf: &Config) -> Result<Self, Error> {
+    let rt = runtime()?;
+    let inner = rt.block_on(crate::Client::receiver_mgmtif(conf))?;
+    Ok(Client { inner, rt })
+  }
+
+
+  /// Return the address the client is connected to.
+  pub fn protaddr(&self) -> &ProtAddr {
+    self.inner.protaddr()
+  }
+
+  /// Return the connection owner, as it was reported by the most recent call
+  /// to [`whoami()`](Self::whoami).
+  pub fn owner(&self) -> Option<&WhoAmI> {
+    self.inner.owner()
+  }
+
+  /// Return the connection's traffic counters.
+  pub fn stats(&self) -> &Arc<Stats> {
+    self.inner.stats()
+  }
+
+  /// Consume the client and return the underlying asynchronous client,
+  /// along with the runtime its connection is registered with.
+  ///
+  /// The asynchronous client must be driven by the returned runtime; its
+  /// connection stops working once the runtime is dropped.
+  pub fn into_inner(self) -> (crate::Client, Runtime) {
+    (self.inner, self.rt)
+  }
+
+  /// Change the connection's timeouts.
+  pub fn set_timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+    self.inner.set_timeouts(timeouts);
+    self
+  }
+
+  /// Drop the current connection and establish a new one.
+  pub fn reconnect(&mut self) -> Result<(), Error> {
+    self.rt.block_on(self.inner.reconnect())
+  }
+
+
+  /// Authenticate the connection.
+  pub fn authenticate(&mut self, auth: Auth) -> Result<AuthOutcome, Error> {
+    self.rt.block_on(self.inner.authenticate(auth))
+  }
+
+  /// Return ownership of the connection to the built-in _unauthenticated_
+  /// account.
+  pub fn unauthenticate(&mut self) -> Result<(), Error> {
+    self.rt.block_on(self.inner.unauthenticate())
+  }
+
+  /// Ask the server who owns the connection.
+  pub fn whoami(&mut self) -> Result<&WhoAmI, Error> {
+    self.rt.block_on(self.inner.whoami())
+  }
+
+  /// Send a telegram then wait for and return the server's reply.
+  pub fn sendrecv(&mut self, tg: &Telegram) -> Result<Params, Error> {
+    self.rt.block_on(self.inner.sendrecv(tg))
+  }
+
+
+  /// Get information about the server node.
+  pub fn get_nodeinfo(&mut self) -> Result<NodeInfo, Error> {
+    self.rt.block_on(self.inner.get_nodeinfo())
+  }
+
+
+  /// Send a message, including (if applicable) its metadata and payload.
+  ///
+  /// On successful completion returns the transfer identifier.
+  pub fn send(
+    &mut self,
+    xfer: &send::Transport,
+    mi: &send::MsgInfo
+  ) -> Result<String, Error> {
+    self.rt.block_on(self.inner.send(xfer, mi))
+  }
+
+  /// Subscribe to an application message channel.
+  pub fn subscribe(&mut self, subinfo: recv::SubInfo) -> Result<(), Error> {
+    self.rt.block_on(self.inner.subscribe(subinfo))
+  }
+
+  /// Receive a single message.
+  pub fn recv<S>(&mut self, storeq: S) -> Result<recv::Msg, Error>
+  where
+    S: FnMut(&recv::MsgInfo) -> Result<(StoreType, StoreType), Error>
+  {
+    self.rt.block_on(self.inner.recv(storeq))
+  }
+
+  /// Keep receiving messages until the connection is closed or a killswitch
+  /// is triggered.
+  pub fn recvloop<S, P>(
+    &mut self,
+    kill: Option<killswitch::Shutdown>,
+    storeq: S,
+    procmsg: P
+  ) -> Result<(), Error>
+  where
+    S: FnMut(&recv::MsgInfo) -> Result<(StoreType, StoreType), Error>,
+    P: Fn(recv::Msg) -> Result<(), Error>
+  {
+    self.rt.block_on(self.inner.recvloop(kill, storeq, procmsg))
+  }
+
+
+  /// Get information about an account.
+  ///
+  /// If `acc` is `None` the current connection's owner will be returned.
+  pub fn rdacc(&mut self, acc: Option<ObjRef>) -> Result<acc::Account, Error> {
+    self.rt.block_on(self.inner.rdacc(acc))
+  }
+
+  /// Get information about several accounts using pipelined requests.
+  pub fn rdacc_many(
+    &mut self,
+    accs: Vec<ObjRef>
+  ) -> Result<Vec<Result<acc::Account, Error>>, Error> {
+    self.rt.block_on(self.inner.rdacc_many(accs))
+  }
+
+  /// Get a list of accounts.
+  pub fn lsacc(&mut self, inclock: bool) -> Result<Vec<acc::LsEntry>, Error> {
+    self.rt.block_on(self.inner.lsacc(inclock))
+  }
+
+  /// Update an account.
+  pub fn wracc(
+    &mut self,
+    acc: ObjRef,
+    ai: acc::WrAccount
+  ) -> Result<(), Error> {
+    self.rt.block_on(self.inner.wracc(acc, ai))
+  }
+
+  /// Remove an account.
+  pub fn rmacc(&mut self, acc: ObjRef) -> Result<(), Error> {
+    self.rt.block_on(self.inner.rmacc(acc))
+  }
+}
+
+
+/// Connect, optionally authenticate, send message and disconnect.
+///
+/// Blocking version of [`msg::send::connsend()`](crate::msg::send::connsend).
+pub fn connsend<P, X, M>(
+  pa: P,
+  auth: Option<&Auth>,
+  xfer: X,
+  mi: M
+) -> Result<String, Error>
+where
+  P: Borrow<ProtAddr>,
+  X: Borrow<send::Transport>,
+  M: Borrow<send::MsgInfo>
+{
+  runtime()?.block_on(send::connsend(pa, auth, xfer, mi))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::testing::MockServer;
+
+  #[test]
+  fn blocking_client() {
+    // The mock server runs on its own runtime's worker threads.
+    let srvrt = runtime::Runtime::new().unwrap();
+    let srv = srvrt.block_on(MockServer::tcp()).unwrap();
+    let id = srv.add_account("alice", "secret", &["mgmt"]);
+
+    let auth = Auth {
+      name: Some("alice".into()),
+      pass: Some("secret".into()),
+      ..Auth::default()
+    };
+    let mut client = Client::connect(srv.protaddr(), None).unwrap();
+    client.authenticate(auth).unwrap();
+    assert_eq!(client.whoami().unwrap().id, id);
+
+    let accs = client.lsacc(false).unwrap();
+    assert!(accs.iter().any(|e| e.name == "alice"));
+    let acc = client.rdacc(Some(ObjRef::Name("alice".into()))).unwrap();
+    assert_eq!(acc.id, id);
+
+    // The asynchronous client keeps working on the returned runtime.
+    let (mut inner, rt) = client.into_inner();
+    assert_eq!(rt.block_on(inner.whoami()).unwrap().id, id);
+    rt.block_on(inner.rmacc(ObjRef::Id(id))).unwrap();
+    assert!(rt.block_on(inner.rdacc(Some(ObjRef::Id(id)))).is_err());
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
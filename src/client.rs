@@ -0,0 +1,405 @@
+//! High-level client session.
+//!
+//! The functions in the [`conn`], [`auth`], [`msg`], [`probe`] and
+//! [`mgmt`](crate::mgmt) modules all operate on a raw framed connection.
+//! The [`Client`] type wraps such a connection together with the address and
+//! authentication context it was established with, and exposes the same
+//! operations as methods.
+//!
+//! Because a `Client` knows how its connection was established it can also
+//! [`reconnect()`](Client::reconnect) after the connection has been lost.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use blather::{Params, Telegram};
+
+use crate::auth::{self, Auth, AuthOutcome};
+use crate::conf::Config;
+use crate::conn::{
+  self,
+  reconn::{Attempt, Backoff, Reconnector},
+  stats::Stats,
+  Frm, Options, ProtAddr, Timeouts, WhoAmI
+};
+use crate::err::Error;
+use crate::mgmt::acc;
+use crate::msg::{
+  self,
+  recv::{self, StoreType},
+  send
+};
+use crate::probe::{self, NodeInfo};
+use crate::types::ObjRef;
+
+
+/// A connection to one of a DDMW core server's client interfaces.
+pub struct Client {
+  conn: Frm,
+  rc: Reconnector,
+  owner: Option<WhoAmI>,
+
+  /// Receiver for incoming messages, which keeps track of a partially
+  /// received message between calls.
+  rx: recv::Receiver
+}
+
+impl Client {
+  /// Connect to the client interface at `pa`, and authenticate using `auth`
+  /// if it has `Some` value.
+  ///
+  /// See [`conn::connect()`] for details.
+  pub async fn connect(
+    pa: ProtAddr,
+    auth: Option<Auth>
+  ) -> Result<Self, Error> {
+    Self::connect_with(pa, auth, Options::default()).await
+  }
+
+  /// Same as [`connect()`](Self::connect), but allows connection options to
+  /// be specified.
+  pub async fn connect_with(
+    pa: ProtAddr,
+    auth: Option<Auth>,
+    opts: Options
+  ) -> Result<Self, Error> {
+    let mut rc = Reconnector::new(pa);
+    rc.set_opts(opts).set_auth(auth);
+    let conn = rc.connect_once().await?;
+    Ok(Client {
+      conn,
+      rc,
+      owner: None,
+      rx: recv::Receiver::new()
+    })
+  }
+
+  /// Connect to the sender node's message interface, as specified in the
+  /// `[sender]` section's `msgif` of the configuration.
+  pub async fn sender_msgif(conf: &Config) -> Result<Self, Error> {
+    let pa = conf
+      .get_sender_msgif()?
+      .ok_or_else(|| Error::miss_data("sender msgif"))?;
+    let opts = conf.get_sender_opts()?;
+    Self::connect_with(pa, conf.auth.clone(), opts).await
+  }
+
+  /// Connect to the sender node's management interface, as specified in the
+  /// `[sender]` section's `mgmtif` of the configuration.
+  pub async fn sender_mgmtif(conf: &Config) -> Result<Self, Error> {
+    let pa = conf
+      .get_sender_mgmtif()?
+      .ok_or_else(|| Error::miss_data("sender mgmtif"))?;
+    let opts = conf.get_sender_opts()?;
+    Self::connect_with(pa, conf.auth.clone(), opts).await
+  }
+
+  /// Connect to the receiver node's subscription interface, as specified in
+  /// the `[receiver]` section's `subif` of the configuration.
+  pub async fn receiver_subif(conf: &Config) -> Result<Self, Error> {
+    let pa = conf
+      .get_receiver_subif()?
+      .ok_or_else(|| Error::miss_data("receiver subif"))?;
+    let opts = conf.get_receiver_opts()?;
+    Self::connect_with(pa, conf.auth.clone(), opts).await
+  }
+
+  /// Connect to the receiver node's management interface, as specified in
+  /// the `[receiver]` section's `mgmtif` of the configuration.
+  pub async fn receiver_mgmtif(conf: &Config) -> Result<Self, Error> {
+    let pa = conf
+      .get_receiver_mgmtif()?
+      .ok_or_else(|| Error::miss_data("receiver mgmtif"))?;
+    let opts = conf.get_receiver_opts()?;
+    Self::connect_with(pa, conf.auth.clone(), opts).await
+  }
+
+
+  /// Return the address the client is connected to.
+  pub fn protaddr(&self) -> &ProtAddr {
+    self.rc.protaddr()
+  }
+
+  /// Return the authentication context the connection was last
+  /// authenticated with.
+  pub fn auth(&self) -> Option<&Auth> {
+    self.rc.auth()
+  }
+
+  /// Return the message channel subscription that will be restored when
+  /// reconnecting.
+  pub fn sub(&self) -> Option<&recv::SubInfo> {
+    self.rc.sub()
+  }
+
+  /// Return the connection owner, as it was reported by the most recent call
+  /// to [`whoami()`](Self::whoami).
+  ///
+  /// Returns `None` if the owner has not been queried since the connection
+  /// was established or since its ownership last changed.
+  pub fn owner(&self) -> Option<&WhoAmI> {
+    self.owner.as_ref()
+  }
+
+  /// Return the connection's traffic counters.
+  ///
+  /// The counters are carried over to new connections established by
+  /// [`reconnect()`](Self::reconnect).
+  pub fn stats(&self) -> &Arc<Stats> {
+    self.conn.codec().stats()
+  }
+
+  /// Get a mutable reference to the underlying framed connection.
+  pub fn conn_mut(&mut self) -> &mut Frm {
+    &mut self.conn
+  }
+
+  /// Consume the client and return the underlying framed connection.
+  ///
+  /// If a message is partially received the connection is poisoned, since
+  /// the rest of the message can no longer be received.
+  pub fn into_inner(mut self) -> Frm {
+    recv::forget(self.rx, &mut self.conn);
+    self.conn
+  }
+
+
+  /// Change the connection's timeouts.
+  ///
+  /// The new timeouts take effect immediately, and will also be used for
+  /// connections established by [`reconnect()`](Self::reconnect).
+  pub fn set_timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+    self.conn.codec_mut().set_timeouts(timeouts.clone());
+    self.rc.opts_mut().timeouts = timeouts;
+    self
+  }
+
+  /// Set the backoff policy used by [`reconnect()`](Self::reconnect).
+  pub fn set_backoff(&mut self, backoff: Backoff) -> &mut Self {
+    self.rc.set_backoff(backoff);
+    self
+  }
+
+  /// Register a closure which is called when each reconnection attempt
+  /// starts, and when it succeeds or fails.
+  pub fn set_reconnect_callback<F>(&mut self, f: F) -> &mut Self
+  where
+    F: FnMut(&Attempt) + Send + 'static
+  {
+    self.rc.set_callback(f);
+    self
+  }
+
+  /// Drop the current connection and establish a new one.
+  ///
+  /// The new connection is authenticated using the last successfully used
+  /// authentication context, and resubscribed to the last subscribed
+  /// application message channel.  Failed attempts are retried according to
+  /// the client's [`Backoff`] policy.  The connection's traffic counters and
+  /// notification handler are carried over to the new connection.
+  ///
+  /// This is typically called after an operation has returned
+  /// [`Error::Disconnected`], [`Error::Poisoned`] or an I/O error.
+  pub async fn reconnect(&mut self) -> Result<(), Error> {
+    self.owner = None;
+    self.rx = recv::Receiver::new();
+    let mut conn = self.rc.connect().await?;
+    let codec = conn.codec_mut();
+    codec.set_stats(Arc::clone(self.stats()));
+    self.conn.codec_mut().move_notify_handler(codec);
+    self.conn = conn;
+    Ok(())
+  }
+
+
+  /// Authenticate the connection, and remember the authentication context on
+  /// success.
+  ///
+  /// See [`Auth::authenticate()`] for details.
+  pub async fn authenticate(
+    &mut self,
+    auth: Auth
+  ) -> Result<AuthOutcome, Error> {
+    self.owner = None;
+    let outcome = auth.authenticate(&mut self.conn).await?;
+    self.rc.set_auth(Some(auth));
+    Ok(outcome)
+  }
+
+  /// Return ownership of the connection to the built-in _unauthenticated_
+  /// account.
+  pub async fn unauthenticate(&mut self) -> Result<(), Error> {
+    self.owner = None;
+    auth::unauthenticate(&mut self.conn).await?;
+    self.rc.set_auth(None);
+    Ok(())
+  }
+
+  /// Ask the server who owns the connection, and remember the result.
+  pub async fn whoami(&mut self) -> Result<&WhoAmI, Error> {
+    let wai = conn::whoami(&mut self.conn).await?;
+    Ok(self.owner.insert(wai))
+  }
+
+  /// Send a telegram then wait for and return the server's reply.
+  ///
+  /// See [`conn::sendrecv()`] for details.
+  pub async fn sendrecv(&mut self, tg: &Telegram) -> Result<Params, Error> {
+    conn::sendrecv(&mut self.conn, tg).await
+  }
+
+
+  /// Get information about the server node.
+  pub async fn get_nodeinfo(&mut self) -> Result<NodeInfo, Error> {
+    probe::get_nodeinfo(&mut self.conn).await
+  }
+
+
+  /// Send a message, including (if applicable) its metadata and payload.
+  ///
+  /// On successful completion returns the transfer identifier.
+  pub async fn send(
+    &mut self,
+    xfer: &send::Transport,
+    mi: &send::MsgInfo
+  ) -> Result<String, Error> {
+    msg::send(&mut self.conn, xfer, mi).await
+  }
+
+  /// Subscribe to an application message channel.
+  ///
+  /// On success the subscription is remembered, and will be restored by
+  /// [`reconnect()`](Self::reconnect).
+  pub async fn subscribe(
+    &mut self,
+    subinfo: recv::SubInfo
+  ) -> Result<(), Error> {
+    recv::subscribe(&mut self.conn, subinfo.clone()).await?;
+    self.rc.set_sub(Some(subinfo));
+    Ok(())
+  }
+
+  /// Receive a single message.
+  ///
+  /// See [`msg::recv::recv()`] for details.  Unlike it, this method is
+  /// cancel safe, and a message interrupted by an idle timeout is resumed by
+  /// the next call; see [`recv::Receiver`].
+  pub async fn recv<S>(&mut self, storeq: S) -> Result<recv::Msg, Error>
+  where
+    S: FnMut(&recv::MsgInfo) -> Result<(StoreType, StoreType), Error>
+  {
+    self.rx.recv(&mut self.conn, storeq).await
+  }
+
+  /// Keep receiving messages until the connection is closed or a killswitch
+  /// is triggered.
+  ///
+  /// See [`msg::recv::recvloop()`] for details.
+  pub async fn recvloop<S, P>(
+    &mut self,
+    kill: Option<killswitch::Shutdown>,
+    storeq: S,
+    procmsg: P
+  ) -> Result<(), Error>
+  where
+    S: FnMut(&recv::MsgInfo) -> Result<(StoreType, StoreType), Error>,
+    P: Fn(recv::Msg) -> Result<(), Error>
+  {
+    recv::recvloop_with(&mut self.rx, &mut self.conn, kill, storeq, procmsg)
+      .await
+  }
+
+  /// Same as [`recvloop()`](Self::recvloop), but the message processing
+  /// closure returns a [`Future`].
+  pub async fn recvloop_a<S, F, P>(
+    &mut self,
+    kill: Option<killswitch::Shutdown>,
+    storeq: S,
+    procmsg: P
+  ) -> Result<(), Error>
+  where
+    S: FnMut(&recv::MsgInfo) -> Result<(StoreType, StoreType), Error>,
+    F: Future<Output = Result<(), Error>>,
+    P: Fn(recv::Msg) -> F
+  {
+    recv::recvloop_a_with(&mut self.rx, &mut self.conn, kill, storeq, procmsg)
+      .await
+  }
+
+
+  /// Get information about an account.
+  ///
+  /// If `acc` is `None` the current connection's owner will be returned.
+  pub async fn rdacc(
+    &mut self,
+    acc: Option<ObjRef>
+  ) -> Result<acc::Account, Error> {
+    acc::rd(&mut self.conn, acc).await
+  }
+
+  /// Get information about several accounts using pipelined requests.
+  ///
+  /// See [`acc::rd_many()`] for details.
+  pub async fn rdacc_many(
+    &mut self,
+    accs: Vec<ObjRef>
+  ) -> Result<Vec<Result<acc::Account, Error>>, Error> {
+    acc::rd_many(&mut self.conn, accs).await
+  }
+
+  /// Get a list of accounts.
+  pub async fn lsacc(
+    &mut self,
+    inclock: bool
+  ) -> Result<Vec<acc::LsEntry>, Error> {
+    acc::ls(&mut self.conn, inclock).await
+  }
+
+  /// Update an account.
+  pub async fn wracc(
+    &mut self,
+    acc: ObjRef,
+    ai: acc::WrAccount
+  ) -> Result<(), Error> {
+    acc::wr(&mut self.conn, acc, ai).await
+  }
+
+  /// Remove an account.
+  pub async fn rmacc(&mut self, acc: ObjRef) -> Result<(), Error> {
+    acc::rm(&mut self.conn, acc).await
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::time::Duration;
+
+  use crate::testing::{Fault, MockServer};
+
+  #[tokio::test]
+  async fn notify_after_reconnect() {
+    let srv = MockServer::tcp().await.unwrap();
+    let mut client = Client::connect(srv.protaddr(), None).await.unwrap();
+    client.set_backoff(Backoff {
+      initial: Duration::from_millis(1),
+      ..Backoff::default()
+    });
+    let mut rx = conn::notifications(client.conn_mut());
+
+    srv.inject("WhoAmI", Fault::Disconnect);
+    assert!(matches!(client.whoami().await, Err(Error::Disconnected)));
+    client.reconnect().await.unwrap();
+
+    // The handler registered on the lost connection receives notifications
+    // sent on the new one.
+    let tg = Telegram::new_topic("Hello").unwrap();
+    srv.inject("WhoAmI", Fault::Notify(tg));
+    client.whoami().await.unwrap();
+    assert_eq!(rx.try_recv().unwrap().get_topic(), Some("Hello"));
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -1,6 +1,8 @@
 //! Load and parse DDMW application configuration file.
 
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use serde::Deserialize;
 
@@ -11,9 +13,10 @@ use figment::{
   Figment
 };
 
-use crate::conn::ProtAddr;
+use crate::conn::{self, ProtAddr, Proxy, TcpConf, Timeouts, TlsConf};
 use crate::err::Error;
 use crate::types::AppChannel;
+use crate::utils;
 
 
 #[derive(Debug, Default, Deserialize)]
@@ -57,17 +60,28 @@ impl Config {
 
   pub fn get_sender_msgif(&self) -> Result<Option<ProtAddr>, Error> {
     if let Some(sender) = &self.sender {
-      if let Some(addr) = &sender.msgif {
-        match addr.parse::<ProtAddr>() {
-          Ok(addr) => {
-            return Ok(Some(addr));
-          }
-          Err(e) => {
-            let err = format!("ProtAddr, {}", e);
-            return Err(Error::parse(err));
-          }
-        }
-      }
+      return parse_protaddr(&sender.msgif);
+    }
+    Ok(None)
+  }
+
+  pub fn get_sender_mgmtif(&self) -> Result<Option<ProtAddr>, Error> {
+    if let Some(sender) = &self.sender {
+      return parse_protaddr(&sender.mgmtif);
+    }
+    Ok(None)
+  }
+
+  pub fn get_receiver_mgmtif(&self) -> Result<Option<ProtAddr>, Error> {
+    if let Some(receiver) = &self.receiver {
+      return parse_protaddr(&receiver.mgmtif);
+    }
+    Ok(None)
+  }
+
+  pub fn get_receiver_subif(&self) -> Result<Option<ProtAddr>, Error> {
+    if let Some(receiver) = &self.receiver {
+      return parse_protaddr(&receiver.subif);
     }
     Ok(None)
   }
@@ -86,7 +100,7 @@ impl Config {
       self.auth = Some(Auth::default());
     }
     if let Some(ref mut auth) = self.auth {
-      auth.pass = Some(pass.to_string());
+      auth.pass = Some(pass.into());
     }
     self
   }
@@ -104,7 +118,7 @@ impl Config {
       self.auth = Some(Auth::default());
     }
     if let Some(ref mut auth) = self.auth {
-      auth.token = Some(tkn.to_string());
+      auth.token = Some(tkn.into());
     }
     self
   }
@@ -117,12 +131,163 @@ impl Config {
     }
     self
   }
+
+  /// Get the connection options configured in the `[sender]` section.
+  pub fn get_sender_opts(&self) -> Result<conn::Options, Error> {
+    match &self.sender {
+      Some(sender) => sender.conn.get_opts(),
+      None => Ok(conn::Options::default())
+    }
+  }
+
+  /// Get the connection options configured in the `[receiver]` section.
+  pub fn get_receiver_opts(&self) -> Result<conn::Options, Error> {
+    match &self.receiver {
+      Some(receiver) => receiver.conn.get_opts(),
+      None => Ok(conn::Options::default())
+    }
+  }
 }
 
+
+/// Connection settings which can be specified in both the `[sender]` and
+/// `[receiver]` sections, and apply to all of that node's interfaces.
+#[derive(Debug, Default, Deserialize)]
+pub struct ConnConf {
+  /// Maximum time to wait for a connection to be established, for example
+  /// `"10 seconds"`.
+  #[serde(rename = "connect-timeout")]
+  pub connect_timeout: Option<String>,
+
+  /// Maximum time to wait for the server to reply to a request.
+  #[serde(rename = "reply-timeout")]
+  pub reply_timeout: Option<String>,
+
+  /// Maximum time to wait for each frame of a message's metadata or
+  /// payload.
+  #[serde(rename = "idle-timeout")]
+  pub idle_timeout: Option<String>,
+
+  /// Maximum time writing a telegram or message content may take.
+  #[serde(rename = "write-timeout")]
+  pub write_timeout: Option<String>,
+
+  /// PEM file with the CA certificate(s) used to verify `tls://` servers.
+  #[serde(rename = "tls-ca")]
+  pub tls_ca: Option<PathBuf>,
+
+  /// PEM file with the client certificate chain for `tls://` connections.
+  #[serde(rename = "tls-cert")]
+  pub tls_cert: Option<PathBuf>,
+
+  /// PEM file with the client certificate's private key.
+  #[serde(rename = "tls-key")]
+  pub tls_key: Option<PathBuf>,
+
+  /// Override the server name used to verify `tls://` servers.
+  #[serde(rename = "tls-sni")]
+  pub tls_sni: Option<String>,
+
+  /// HTTP proxy to tunnel TCP/IP connections through, as a
+  /// `http://[user:pass@]host:port` URL, or `"env"` to use the standard
+  /// proxy environment variables.
+  pub proxy: Option<String>,
+
+  /// Local address to bind TCP/IP connections to, either as an IP address
+  /// or as an `<ip>:<port>` socket address.
+  pub bind: Option<String>,
+
+  /// Network interface to bind TCP/IP connections to.  Only supported on
+  /// Linux.
+  #[serde(rename = "bind-device")]
+  pub bind_device: Option<String>,
+
+  /// Enable TCP keepalive, with probes starting after the connection has
+  /// been idle for this long, for example `"1 minute"`.
+  pub keepalive: Option<String>,
+
+  /// Disable Nagle's algorithm on TCP/IP connections.
+  pub nodelay: Option<bool>
+}
+
+impl ConnConf {
+  /// Parse the configured timeouts.
+  pub fn get_timeouts(&self) -> Result<Timeouts, Error> {
+    let parse = |s: &Option<String>| -> Result<Option<Duration>, Error> {
+      match s {
+        Some(s) => Ok(Some(utils::parse_duration(s)?)),
+        None => Ok(None)
+      }
+    };
+    Ok(Timeouts {
+      connect: parse(&self.connect_timeout)?,
+      reply: parse(&self.reply_timeout)?,
+      idle: parse(&self.idle_timeout)?,
+      write: parse(&self.write_timeout)?
+    })
+  }
+
+  /// Get the configured TLS settings.
+  pub fn get_tls(&self) -> TlsConf {
+    TlsConf {
+      ca: self.tls_ca.clone(),
+      cert: self.tls_cert.clone(),
+      key: self.tls_key.clone(),
+      sni: self.tls_sni.clone()
+    }
+  }
+
+  /// Parse the configured TCP/IP socket settings.
+  pub fn get_tcp(&self) -> Result<TcpConf, Error> {
+    let bind = match &self.bind {
+      Some(s) => match s.parse::<IpAddr>() {
+        Ok(ip) => Some(SocketAddr::new(ip, 0)),
+        Err(_) => Some(
+          s.parse::<SocketAddr>()
+            .map_err(|e| Error::parse(format!("bind, {}", e)))?
+        )
+      },
+      None => None
+    };
+    let keepalive = match &self.keepalive {
+      Some(s) => Some(utils::parse_duration(s)?),
+      None => None
+    };
+    Ok(TcpConf {
+      bind,
+      device: self.bind_device.clone(),
+      keepalive,
+      nodelay: self.nodelay
+    })
+  }
+
+  /// Parse the configured proxy.
+  pub fn get_proxy(&self) -> Result<Option<Proxy>, Error> {
+    match &self.proxy {
+      Some(proxy) => Ok(Some(proxy.parse()?)),
+      None => Ok(None)
+    }
+  }
+
+  /// Generate a [`conn::Options`] buffer from the configured settings.
+  pub fn get_opts(&self) -> Result<conn::Options, Error> {
+    Ok(conn::Options {
+      timeouts: self.get_timeouts()?,
+      tls: self.get_tls(),
+      tcp: self.get_tcp()?,
+      proxy: self.get_proxy()?,
+      ..conn::Options::default()
+    })
+  }
+}
+
+
 #[derive(Debug, Default, Deserialize)]
 pub struct Sender {
   pub mgmtif: Option<String>,
-  pub msgif: Option<String>
+  pub msgif: Option<String>,
+  #[serde(flatten)]
+  pub conn: ConnConf
 }
 
 
@@ -135,7 +300,36 @@ pub struct Receiver {
   #[serde(rename = "sub-retry-delay")]
   pub sub_retry_delay: Option<String>,
   #[serde(rename = "push-listenif")]
-  pub push_listenif: Option<String>
+  pub push_listenif: Option<String>,
+  #[serde(flatten)]
+  pub conn: ConnConf
+}
+
+impl Receiver {
+  /// Parse the `sub-retry-delay` field, if set, into a [`Duration`].
+  pub fn get_sub_retry_delay(&self) -> Result<Option<Duration>, Error> {
+    match &self.sub_retry_delay {
+      Some(delay) => Ok(Some(utils::parse_duration(delay)?)),
+      None => Ok(None)
+    }
+  }
+}
+
+
+/// Parse an optional interface address string into a [`ProtAddr`].
+fn parse_protaddr(addr: &Option<String>) -> Result<Option<ProtAddr>, Error> {
+  if let Some(addr) = addr {
+    match addr.parse::<ProtAddr>() {
+      Ok(addr) => {
+        return Ok(Some(addr));
+      }
+      Err(e) => {
+        let err = format!("ProtAddr, {}", e);
+        return Err(Error::parse(err));
+      }
+    }
+  }
+  Ok(None)
 }
 
 
@@ -1,36 +1,63 @@
 //! Methods used to establish connections to DDMW Core servers' client
 //! interfaces.
 
+mod codec;
+pub mod pipeline;
+pub mod proxy;
+pub mod reconn;
+pub mod record;
+pub mod stats;
+mod stream;
+#[cfg(feature = "tls")]
+mod tls;
+
 use std::borrow::Borrow;
 use std::fmt;
+use std::future::Future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 
 #[cfg(unix)]
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
 use futures::sink::SinkExt;
 
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::mpsc;
 
 #[cfg(unix)]
 use tokio::net::UnixStream;
 
 use tokio_stream::StreamExt;
 
-use tokio_util::codec::Framed;
+use tokio_util::codec::{Encoder, Framed};
 
-#[cfg(unix)]
-use tokio_util::either::Either;
+use tracing::Instrument;
 
-use blather::{codec, Telegram};
+use blather::{codec::Input, Telegram};
 
 use crate::auth::Auth;
 
 use crate::err::Error;
+use crate::trace;
+
+pub use codec::Codec;
+pub use proxy::Proxy;
+pub use stream::Stream;
+
+use record::Direction;
+
+use stream::Io;
 
 
 /// Protocol selection enum.
+#[derive(Clone, Debug)]
 pub enum ProtAddr {
   /// Connect over TCP/IP.  The `String` is a socket address in the form
   /// `<host>:<port>`.
@@ -39,7 +66,27 @@ pub enum ProtAddr {
   /// Connect over unix local domain sockets.  The `PathBuf` is a file system
   /// socket path.
   #[cfg(unix)]
-  Uds(PathBuf)
+  Uds(PathBuf),
+
+  /// Connect over a unix local domain socket in Linux' abstract namespace.
+  /// The `String` is the socket name, without the leading `@`.
+  #[cfg(target_os = "linux")]
+  Abstract(String),
+
+  /// Use an already connected socket inherited from the parent process, for
+  /// example from a service manager.  The `RawFd` is the socket's file
+  /// descriptor, which may be either a TCP/IP or a unix local domain socket.
+  ///
+  /// The descriptor is duplicated for each connection, so the original
+  /// remains owned by the process.
+  #[cfg(unix)]
+  Fd(RawFd),
+
+  /// Connect over TLS on top of TCP/IP.  The `String` is a socket address in
+  /// the form `<host>:<port>`.  The TLS settings are taken from
+  /// [`Options::tls`].
+  #[cfg(feature = "tls")]
+  Tls(String)
 }
 
 impl FromStr for ProtAddr {
@@ -47,50 +94,307 @@ impl FromStr for ProtAddr {
 
   /// Parse a `&str` and turn it into a `ProtAddr`.
   ///
-  /// On unixy platforms if the `addr` contains one or more slashes (`/`) it is
-  /// assumed the address is a unix local domain socket address.  Otherwise
-  /// it is assumed the address is an IP socket address, in the form
-  /// `<host>:<port>`.
+  /// The address type can be selected explicitly using a scheme prefix:
+  /// - `tcp://<host>:<port>` selects a TCP/IP connection.
+  /// - `unix://<path>` selects a unix local domain socket connection.  Both
+  ///   relative (`unix://ddmw.sock`) and absolute (`unix:///run/ddmw.sock`)
+  ///   paths are supported.
+  /// - `tls://<host>:<port>` selects a TLS connection (requires the `tls`
+  ///   feature).
+  /// - `fd://<n>` selects an already connected socket inherited as file
+  ///   descriptor `<n>` (unix only).
+  /// - `@<name>` selects a Linux abstract namespace unix socket.
+  ///
+  /// IPv6 hosts must be enclosed in brackets, as in `[::1]:4100`.
+  ///
+  /// If no scheme is specified, and on unixy platforms the `addr` contains
+  /// one or more slashes (`/`), it is assumed the address is a unix local
+  /// domain socket address.  Otherwise it is assumed the address is an IP
+  /// socket address, in the form `<host>:<port>`.
+  ///
+  /// Socket addresses are validated, and `Error::Parse` is returned if they
+  /// are malformed.
   fn from_str(addr: &str) -> Result<Self, Self::Err> {
+    if let Some((scheme, rest)) = addr.split_once("://") {
+      return match scheme {
+        "tcp" => Ok(ProtAddr::Tcp(parse_sockaddr(rest)?)),
+        "unix" => parse_uds(rest),
+        "fd" => parse_fd(rest),
+        "tls" => {
+          #[cfg(feature = "tls")]
+          return Ok(ProtAddr::Tls(parse_sockaddr(rest)?));
+
+          #[cfg(not(feature = "tls"))]
+          return Err(Error::parse("TLS support has not been enabled"));
+        }
+        _ => Err(Error::parse(format!("Unknown address scheme '{}'", scheme)))
+      };
+    }
+
+    if let Some(name) = addr.strip_prefix('@') {
+      return parse_abstract(name);
+    }
+
     #[cfg(unix)]
-    if let Some(_) = addr.find("/") {
+    if addr.contains('/') {
       // Assume local domain socket
-      Ok(ProtAddr::Uds(PathBuf::from(addr)))
-    } else {
-      // Assume IP socket address
-      Ok(ProtAddr::Tcp(addr.to_string()))
+      return Ok(ProtAddr::Uds(PathBuf::from(addr)));
     }
 
-    #[cfg(windows)]
-    Ok(ProtAddr::Tcp(addr.to_string()))
+    // Assume IP socket address
+    Ok(ProtAddr::Tcp(parse_sockaddr(addr)?))
   }
 }
 
+
+/// Parse a `unix://` address' path.
+fn parse_uds(path: &str) -> Result<ProtAddr, Error> {
+  if path.is_empty() {
+    return Err(Error::parse("Missing socket path"));
+  }
+
+  #[cfg(unix)]
+  return Ok(ProtAddr::Uds(PathBuf::from(path)));
+
+  #[cfg(not(unix))]
+  Err(Error::parse(
+    "Unix local domain sockets are not supported on this platform"
+  ))
+}
+
+
+/// Parse an `@` address' abstract socket name.
+fn parse_abstract(name: &str) -> Result<ProtAddr, Error> {
+  if name.is_empty() {
+    return Err(Error::parse("Missing abstract socket name"));
+  }
+
+  #[cfg(target_os = "linux")]
+  return Ok(ProtAddr::Abstract(name.to_string()));
+
+  #[cfg(not(target_os = "linux"))]
+  Err(Error::parse(
+    "Abstract namespace sockets are not supported on this platform"
+  ))
+}
+
+
+/// Parse an `fd://` address' file descriptor number.
+fn parse_fd(fd: &str) -> Result<ProtAddr, Error> {
+  let fd = fd
+    .parse::<i32>()
+    .ok()
+    .filter(|fd| *fd >= 0)
+    .ok_or_else(|| {
+      Error::parse(format!("Invalid file descriptor '{}'", fd))
+    })?;
+
+  #[cfg(unix)]
+  return Ok(ProtAddr::Fd(fd));
+
+  #[cfg(not(unix))]
+  Err(Error::parse(format!(
+    "Inherited file descriptors ({}) are not supported on this platform",
+    fd
+  )))
+}
+
+
+/// Validate a `<host>:<port>` socket address, and return it in a form that
+/// can be passed to [`TcpStream::connect()`].
+fn parse_sockaddr(addr: &str) -> Result<String, Error> {
+  if addr.is_empty() {
+    return Err(Error::parse("Empty socket address"));
+  }
+
+  let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+    // Bracketed IPv6 address
+    let end = rest.find(']').ok_or_else(|| {
+      Error::parse(format!("Missing ']' in IPv6 address '{}'", addr))
+    })?;
+    let host = &rest[..end];
+    if host.parse::<Ipv6Addr>().is_err() {
+      return Err(Error::parse(format!("Invalid IPv6 address '{}'", host)));
+    }
+    let port = rest[end + 1..]
+      .strip_prefix(':')
+      .ok_or_else(|| Error::parse(format!("Missing port in '{}'", addr)))?;
+    (format!("[{}]", host), port)
+  } else {
+    let (host, port) = addr.rsplit_once(':').ok_or_else(|| {
+      Error::parse(format!(
+        "Missing port in '{}'; expected <host>:<port> (use unix:// for \
+         socket paths)",
+        addr
+      ))
+    })?;
+    if host.contains(':') {
+      return Err(Error::parse(format!(
+        "IPv6 address in '{}' must be enclosed in brackets",
+        addr
+      )));
+    }
+    validate_host(host)?;
+    (host.to_string(), port)
+  };
+
+  let port = port
+    .parse::<u16>()
+    .map_err(|_| Error::parse(format!("Invalid port '{}'", port)))?;
+  if port == 0 {
+    return Err(Error::parse("Port must not be zero"));
+  }
+
+  Ok(format!("{}:{}", host, port))
+}
+
+
+/// Make sure `host` is either an IPv4 address or a valid host name.
+fn validate_host(host: &str) -> Result<(), Error> {
+  if host.is_empty() {
+    return Err(Error::parse("Missing host"));
+  }
+  if host.parse::<Ipv4Addr>().is_ok() {
+    return Ok(());
+  }
+  if host
+    .split('.')
+    .all(|l| l.chars().all(|c| c.is_ascii_digit()))
+  {
+    return Err(Error::parse(format!("Invalid IPv4 address '{}'", host)));
+  }
+  let valid_label = |l: &str| {
+    !l.is_empty()
+      && l.len() <= 63
+      && !l.starts_with('-')
+      && !l.ends_with('-')
+      && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+  };
+  if host.len() > 253 || !host.split('.').all(valid_label) {
+    return Err(Error::parse(format!("Invalid host name '{}'", host)));
+  }
+  Ok(())
+}
+
 impl fmt::Display for ProtAddr {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
       #[cfg(unix)]
       ProtAddr::Uds(sa) => {
         // ToDo: Return error if it's not really a valid Unicode string.
-        write!(f, "{}", sa.display())
+        if sa.to_string_lossy().contains('/') {
+          write!(f, "{}", sa.display())
+        } else {
+          // Without a slash the path would be mistaken for a TCP/IP address
+          // when parsed.
+          write!(f, "unix://{}", sa.display())
+        }
+      }
+      #[cfg(target_os = "linux")]
+      ProtAddr::Abstract(name) => {
+        write!(f, "@{}", name)
+      }
+      #[cfg(unix)]
+      ProtAddr::Fd(fd) => {
+        write!(f, "fd://{}", fd)
       }
       ProtAddr::Tcp(sa) => {
         write!(f, "{}", sa)
       }
+      #[cfg(feature = "tls")]
+      ProtAddr::Tls(sa) => {
+        write!(f, "tls://{}", sa)
+      }
     }
   }
 }
 
 
-/// Framed type alias for Unix platforms where a connection can be either
-/// TcpStream or UnixStream.
-#[cfg(unix)]
-pub type Frm = Framed<Either<TcpStream, UnixStream>, blather::Codec>;
+/// Framed type alias for connections established by [`connect()`].
+pub type Frm = Framed<Stream, Codec>;
 
-/// Framed type alias for Windows.  This exists merely due to UnixStream not
-/// currently being available on Windows.  Once it is, this should be removed.
-#[cfg(windows)]
-pub type Frm = Framed<TcpStream, blather::Codec>;
+
+/// Connection timeouts.  A `None` value means wait indefinitely.
+///
+/// After a reply or write timeout the connection is left in an unknown state,
+/// and further requests on it fail with [`Error::Poisoned`].
+#[derive(Clone, Debug, Default)]
+pub struct Timeouts {
+  /// Maximum time to wait for a connection to be established.
+  pub connect: Option<Duration>,
+
+  /// Maximum time to wait for the server to reply to a request.
+  pub reply: Option<Duration>,
+
+  /// Maximum time to wait for each frame of message metadata or payload.
+  /// Metadata and payload which are not received in chunks each arrive as a
+  /// single frame.
+  pub idle: Option<Duration>,
+
+  /// Maximum time writing a telegram or message content to the connection
+  /// may take.
+  pub write: Option<Duration>
+}
+
+
+/// TLS settings.  These are only used for [`ProtAddr::Tls`](ProtAddr)
+/// connections, which require the `tls` feature.
+#[derive(Clone, Debug, Default)]
+pub struct TlsConf {
+  /// PEM file containing the CA certificate(s) which the server's
+  /// certificate must have been issued by.  If not set, the platform's
+  /// trusted root certificates are used.
+  pub ca: Option<PathBuf>,
+
+  /// PEM file containing the client certificate chain, for servers which
+  /// require client authentication.  Requires `key` to be set as well.
+  pub cert: Option<PathBuf>,
+
+  /// PEM file containing the client certificate's private key.
+  pub key: Option<PathBuf>,
+
+  /// Server name to send and verify, instead of the host part of the
+  /// address.
+  pub sni: Option<String>
+}
+
+
+/// TCP/IP socket settings.  These are used for [`ProtAddr::Tcp`](ProtAddr)
+/// and `ProtAddr::Tls` connections, and for connections to proxies.
+#[derive(Clone, Debug, Default)]
+pub struct TcpConf {
+  /// Local address to bind the socket to before connecting.  The port may
+  /// be `0` to let the system choose one.
+  pub bind: Option<SocketAddr>,
+
+  /// Network interface to bind the socket to (`SO_BINDTODEVICE`).  Only
+  /// supported on Linux.
+  pub device: Option<String>,
+
+  /// Enable TCP keepalive, and send the first probe after the connection
+  /// has been idle for this long.
+  pub keepalive: Option<Duration>,
+
+  /// Set `TCP_NODELAY`, disabling Nagle's algorithm.
+  pub nodelay: Option<bool>
+}
+
+
+/// Connection options.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+  pub timeouts: Timeouts,
+  pub tls: TlsConf,
+  pub tcp: TcpConf,
+
+  /// Record the connection's traffic to this capture file.  See the
+  /// [`record`] module.
+  pub record: Option<PathBuf>,
+
+  /// Tunnel [`ProtAddr::Tcp`](ProtAddr) connections through an HTTP proxy.
+  /// See the [`proxy`] module.
+  pub proxy: Option<Proxy>
+}
 
 
 /// Connect to one of the DDMW core's client interfaces and optionally attempt
@@ -110,12 +414,50 @@ pub async fn connect<P>(pa: P, auth: Option<&Auth>) -> Result<Frm, Error>
 where
   P: Borrow<ProtAddr>
 {
-  let mut framed = match pa.borrow() {
-    ProtAddr::Tcp(sa) => connect_tcp(sa).await?,
+  connect_with(pa, auth, &Options::default()).await
+}
 
-    #[cfg(unix)]
-    ProtAddr::Uds(sa) => connect_uds(sa).await?
-  };
+
+/// Same as [`connect()`], but allows connection options to be specified.
+///
+/// The timeouts in `opts` will remain in effect for the returned connection.
+/// They can be changed using [`Codec::set_timeouts()`].
+pub async fn connect_with<P>(
+  pa: P,
+  auth: Option<&Auth>,
+  opts: &Options
+) -> Result<Frm, Error>
+where
+  P: Borrow<ProtAddr>
+{
+  let io = with_timeout(opts.timeouts.connect, "connect", async {
+    match pa.borrow() {
+      ProtAddr::Tcp(sa) => connect_tcp(sa, opts).await,
+
+      #[cfg(unix)]
+      ProtAddr::Uds(sa) => connect_uds(sa).await,
+
+      #[cfg(target_os = "linux")]
+      ProtAddr::Abstract(name) => connect_abstract(name).await,
+
+      #[cfg(unix)]
+      ProtAddr::Fd(fd) => connect_fd(*fd),
+
+      #[cfg(feature = "tls")]
+      ProtAddr::Tls(sa) => {
+        let stream = tls::connect(sa, &opts.tls, &opts.tcp).await?;
+        Ok(Io::Tls(Box::new(stream)))
+      }
+    }
+  })
+  .await?;
+
+  let mut codec = Codec::new();
+  codec.set_timeouts(opts.timeouts.clone());
+  if let Some(fname) = &opts.record {
+    codec.set_recorder(Some(record::Recorder::create(fname)?));
+  }
+  let mut framed = Framed::new(Stream::new(io), codec);
 
   if let Some(auth) = auth {
     auth.authenticate(&mut framed).await?;
@@ -125,73 +467,409 @@ where
 }
 
 
-/// Attempt to establish a TCP/IP socket connection.
-async fn connect_tcp(addr: &str) -> Result<Frm, Error> {
-  let stream = TcpStream::connect(addr).await?;
+/// Attempt to establish a TCP/IP socket connection, either directly or
+/// through a proxy.
+async fn connect_tcp(addr: &str, opts: &Options) -> Result<Io, Error> {
+  let proxy = match &opts.proxy {
+    Some(proxy) => proxy.resolve(addr)?,
+    None => None
+  };
+  let stream = match proxy {
+    Some(proxy) => {
+      tracing::debug!(proxy = %proxy.addr, "connecting through proxy");
+      proxy::connect(&proxy, addr, &opts.tcp).await?
+    }
+    None => open_tcp(addr, &opts.tcp).await?
+  };
+  Ok(Io::Tcp(stream))
+}
 
-  #[cfg(unix)]
-  return Ok(Framed::new(Either::Left(stream), blather::Codec::new()));
 
-  #[cfg(windows)]
-  return Ok(Framed::new(stream, blather::Codec::new()));
+/// Open a TCP/IP socket connection to `addr`, applying the socket settings
+/// in `conf`.
+///
+/// If `addr` resolves to several addresses they are tried in order, skipping
+/// those of a different address family than the local bind address.
+pub(crate) async fn open_tcp(
+  addr: &str,
+  conf: &TcpConf
+) -> Result<TcpStream, Error> {
+  let stream = if conf.bind.is_none() && conf.device.is_none() {
+    TcpStream::connect(addr).await?
+  } else {
+    let mut last_err = None;
+    let mut found = None;
+    for sa in tokio::net::lookup_host(addr).await? {
+      if matches!(conf.bind, Some(b) if b.is_ipv4() != sa.is_ipv4()) {
+        continue;
+      }
+      match connect_bound(sa, conf).await {
+        Ok(stream) => {
+          found = Some(stream);
+          break;
+        }
+        Err(e) => last_err = Some(e)
+      }
+    }
+    match (found, last_err) {
+      (Some(stream), _) => stream,
+      (None, Some(e)) => return Err(e),
+      (None, None) => {
+        return Err(Error::BadParams(format!(
+          "No address of {} matches the local bind address",
+          addr
+        )))
+      }
+    }
+  };
+
+  if let Some(nodelay) = conf.nodelay {
+    stream.set_nodelay(nodelay)?;
+  }
+  if let Some(time) = conf.keepalive {
+    let ka = socket2::TcpKeepalive::new().with_time(time);
+    socket2::SockRef::from(&stream).set_tcp_keepalive(&ka)?;
+  }
+  Ok(stream)
+}
+
+
+/// Connect to `sa` using a socket bound to the local address and/or network
+/// interface in `conf`.
+async fn connect_bound(
+  sa: SocketAddr,
+  conf: &TcpConf
+) -> Result<TcpStream, Error> {
+  let sock = if sa.is_ipv4() {
+    TcpSocket::new_v4()?
+  } else {
+    TcpSocket::new_v6()?
+  };
+
+  if let Some(dev) = &conf.device {
+    #[cfg(target_os = "linux")]
+    sock.bind_device(Some(dev.as_bytes()))?;
+
+    #[cfg(not(target_os = "linux"))]
+    return Err(Error::BadParams(format!(
+      "Binding to device {} is not supported on this platform",
+      dev
+    )));
+  }
+  if let Some(bind) = conf.bind {
+    sock.bind(bind)?;
+  }
+
+  Ok(sock.connect(sa).await?)
 }
 
 
 /// Attempt to establish a unix domain socket connection.
 /// Currently only available on unix-like platforms.
 #[cfg(unix)]
-async fn connect_uds(addr: &Path) -> Result<Frm, Error> {
+async fn connect_uds(addr: &Path) -> Result<Io, Error> {
   let addr = match addr.to_str() {
     Some(a) => a.to_string(),
     None => unreachable!()
   };
   let stream = UnixStream::connect(addr).await?;
-  Ok(Framed::new(Either::Right(stream), blather::Codec::new()))
+  Ok(Io::Uds(stream))
+}
+
+
+/// Attempt to establish a unix domain socket connection to a socket in the
+/// abstract namespace.
+///
+/// The standard library can only connect blocking sockets to abstract
+/// addresses, so the connection is made on tokio's blocking thread pool.
+#[cfg(target_os = "linux")]
+async fn connect_abstract(name: &str) -> Result<Io, Error> {
+  use std::os::linux::net::SocketAddrExt;
+  use std::os::unix::net::{self, SocketAddr};
+
+  let addr = SocketAddr::from_abstract_name(name)?;
+  let stream =
+    tokio::task::spawn_blocking(move || net::UnixStream::connect_addr(&addr))
+      .await
+      .map_err(std::io::Error::other)??;
+  stream.set_nonblocking(true)?;
+  Ok(Io::Uds(UnixStream::from_std(stream)?))
+}
+
+
+/// Wrap a duplicate of an inherited, already connected, socket.
+///
+/// The descriptor number comes from configuration, so nothing is assumed
+/// about it; it is duplicated using `fcntl()`, which fails if it is not an
+/// open descriptor, and the duplicate must turn out to be a socket.
+#[cfg(unix)]
+fn connect_fd(fd: RawFd) -> Result<Io, Error> {
+  use std::os::unix::io::{FromRawFd, OwnedFd};
+  use std::os::unix::net;
+
+  // SAFETY: fcntl() does not access any memory, and reports an error if `fd`
+  // is not an open descriptor.
+  let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+  if dup < 0 {
+    return Err(std::io::Error::last_os_error().into());
+  }
+
+  // SAFETY: `dup` is a newly created descriptor which nothing else owns.
+  let fd = unsafe { OwnedFd::from_raw_fd(dup) };
+
+  let stream = net::UnixStream::from(fd);
+  if stream.local_addr().is_ok() {
+    stream.set_nonblocking(true)?;
+    return Ok(Io::Uds(UnixStream::from_std(stream)?));
+  }
+
+  let stream = std::net::TcpStream::from(OwnedFd::from(stream));
+  stream.local_addr()?;
+  stream.set_nonblocking(true)?;
+  Ok(Io::Tcp(TcpStream::from_std(stream)?))
+}
+
+
+/// Return the host part of a `<host>:<port>` address, without IPv6 brackets.
+pub(crate) fn host_of(addr: &str) -> &str {
+  let host = match addr.rfind(':') {
+    Some(idx) => &addr[..idx],
+    None => addr
+  };
+  host.trim_start_matches('[').trim_end_matches(']')
+}
+
+
+/// Run a future to completion, or fail with [`Error::Timeout`] if `dur` has
+/// `Some` value and the future has not completed within it.
+pub(crate) async fn with_timeout<F, R>(
+  dur: Option<Duration>,
+  what: &str,
+  fut: F
+) -> Result<R, Error>
+where
+  F: Future<Output = Result<R, Error>>
+{
+  match dur {
+    Some(dur) => match tokio::time::timeout(dur, fut).await {
+      Ok(res) => res,
+      Err(_) => Err(Error::Timeout(what.to_string()))
+    },
+    None => fut.await
+  }
+}
+
+
+/// Mark the connection as unusable if `res` is a timeout.  After a reply
+/// timeout the late reply would be taken as the reply to the next request,
+/// and after a write timeout a partially written frame may have been left
+/// behind.
+fn poison_on_timeout<T, R>(
+  conn: &mut Framed<T, Codec>,
+  res: Result<R, Error>
+) -> Result<R, Error> {
+  if let Err(Error::Timeout(_)) = res {
+    conn.codec_mut().poison();
+  }
+  res
+}
+
+
+/// Write a frame to the connection, without flushing it.
+///
+/// If the connection has a write timeout, and the write takes longer than
+/// it, `Err(Error::Timeout)` is returned and the connection is poisoned.
+pub(crate) async fn feed_frame<T, I>(
+  conn: &mut Framed<T, Codec>,
+  item: I
+) -> Result<(), Error>
+where
+  T: AsyncRead + AsyncWrite + Unpin,
+  Codec: Encoder<I, Error = blather::Error>
+{
+  conn.codec().usable()?;
+  let tmo = conn.codec().timeouts().write;
+  let res = with_timeout(tmo, "write", async {
+    conn.feed(item).await?;
+    Ok(())
+  })
+  .await;
+  poison_on_timeout(conn, res)
+}
+
+/// Flush frames written by [`feed_frame()`].
+pub(crate) async fn flush_frames<T>(
+  conn: &mut Framed<T, Codec>
+) -> Result<(), Error>
+where
+  T: AsyncRead + AsyncWrite + Unpin
+{
+  conn.codec().usable()?;
+  let tmo = conn.codec().timeouts().write;
+  let res = with_timeout(tmo, "write", async {
+    SinkExt::<&[u8]>::flush(conn).await?;
+    Ok(())
+  })
+  .await;
+  poison_on_timeout(conn, res)
+}
+
+/// Write a frame to the connection and flush it.
+pub(crate) async fn send_frame<T, I>(
+  conn: &mut Framed<T, Codec>,
+  item: I
+) -> Result<(), Error>
+where
+  T: AsyncRead + AsyncWrite + Unpin,
+  Codec: Encoder<I, Error = blather::Error>
+{
+  feed_frame(conn, item).await?;
+  flush_frames(conn).await
+}
+
+
+/// Wait for the next frame.
+///
+/// If `idle` is `true`, and the connection has an idle timeout, then the
+/// read will fail with [`Error::Timeout`] if no frame has arrived within the
+/// idle timeout.  The connection remains usable after an idle timeout.
+///
+/// Returns `Ok(None)` if the connection was closed.
+pub(crate) async fn next_frame<T>(
+  conn: &mut Framed<T, Codec>,
+  idle: bool
+) -> Result<Option<Input>, Error>
+where
+  T: AsyncRead + AsyncWrite + Unpin
+{
+  conn.codec().usable()?;
+
+  let tmo = match idle {
+    true => conn.codec().timeouts().idle,
+    false => None
+  };
+  let res =
+    with_timeout(tmo, "idle read", async { Ok(conn.next().await) }).await?;
+
+  match res {
+    Some(o) => {
+      let o = o?;
+      if let Input::Telegram(_) = &o {
+        conn.codec().stats().tg(Direction::Recv);
+      }
+      Ok(Some(o))
+    }
+    None => Ok(None)
+  }
 }
 
 
 /// Send a telegram then wait for and return the server's reply.
 /// If the server returns a `Fail`, it will be returned as
 /// `Err(Error::ServerError)`.
+///
+/// If the connection has a reply timeout, and the round trip takes longer
+/// than it, `Err(Error::Timeout)` is returned.  The reply may still arrive
+/// later, so the connection is poisoned and must be dropped.
 pub async fn sendrecv<T: AsyncRead + AsyncWrite + Unpin>(
-  conn: &mut Framed<T, blather::Codec>,
+  conn: &mut Framed<T, Codec>,
   tg: &Telegram
 ) -> Result<blather::Params, Error> {
-  conn.send(tg).await?;
-  expect_okfail(conn).await
+  let span = tracing::debug_span!("request", topic = tg.get_topic());
+  let tmo = conn.codec().timeouts().reply;
+  let res = with_timeout(tmo, "reply", async {
+    tracing::debug!(tg = %trace::tg(tg), "send");
+    let start = Instant::now();
+    send_frame(conn, tg).await?;
+    conn.codec().stats().tg(Direction::Send);
+    let res = recv_okfail(conn).await;
+    if let Ok(_) | Err(Error::ServerError(_)) = &res {
+      conn.codec().stats().rtt(start.elapsed());
+    }
+    res
+  })
+  .instrument(span)
+  .await;
+  poison_on_timeout(conn, res)
 }
 
 
 /// Waits for a message and ensures that it's Ok or Fail.
 /// Converts Fail state to an Error::ServerError.
 /// Returns a Params buffer containig the Ok parameters on success.
+///
+/// Any other telegram that arrives while waiting is passed to the
+/// connection's notification handler, if one has been registered (see
+/// [`notifications()`]).  Otherwise `Err(Error::BadState)` is returned.
+///
+/// If the connection has a reply timeout, and no reply has arrived within
+/// it, `Err(Error::Timeout)` is returned and the connection is poisoned.
 pub async fn expect_okfail<T: AsyncRead + AsyncWrite + Unpin>(
-  conn: &mut Framed<T, blather::Codec>
+  conn: &mut Framed<T, Codec>
 ) -> Result<blather::Params, Error> {
-  if let Some(o) = conn.next().await {
-    let o = o?;
-    match o {
-      codec::Input::Telegram(tg) => {
-        if let Some(topic) = tg.get_topic() {
-          if topic == "Ok" {
-            return Ok(tg.into_params());
-          } else if topic == "Fail" {
-            return Err(Error::ServerError(tg.into_params()));
-          }
-        }
-      }
-      _ => {
-        println!("unexpected reply");
+  let tmo = conn.codec().timeouts().reply;
+  let res = with_timeout(tmo, "reply", recv_okfail(conn)).await;
+  poison_on_timeout(conn, res)
+}
+
+
+pub(crate) async fn recv_okfail<T: AsyncRead + AsyncWrite + Unpin>(
+  conn: &mut Framed<T, Codec>
+) -> Result<blather::Params, Error> {
+  loop {
+    let tg = match next_frame(conn, false).await? {
+      Some(Input::Telegram(tg)) => tg,
+      Some(_) => {
+        tracing::warn!("unexpected reply; not a telegram");
+        return Err(Error::bad_state("Unexpected reply from server."));
       }
+      None => return Err(Error::Disconnected)
+    };
+    tracing::debug!(tg = %trace::tg(&tg), "recv");
+    match tg.get_topic() {
+      Some("Ok") => return Ok(tg.into_params()),
+      Some("Fail") => return Err(Error::ServerError(tg.into_params())),
+      _ => {}
+    }
+    if let Err(tg) = dispatch_notification(conn, tg) {
+      tracing::warn!(topic = tg.get_topic(), "unexpected reply");
+      return Err(Error::bad_state("Unexpected reply from server."));
     }
-    return Err(Error::BadState("Unexpected reply from server.".to_string()));
   }
+}
+
 
-  Err(Error::Disconnected)
+/// Route incoming telegrams that are neither replies nor messages to a
+/// channel, and return its receiving end.
+///
+/// This replaces any notification handler previously registered on the
+/// connection.  Notifications are only processed while the connection is
+/// waiting for a reply or a message; an application that only listens for
+/// notifications needs to keep a call to, for instance,
+/// [`msg::recv()`](crate::msg::recv()) in progress.
+pub fn notifications<T>(
+  conn: &mut Framed<T, Codec>
+) -> mpsc::UnboundedReceiver<Telegram> {
+  let (tx, rx) = mpsc::unbounded_channel();
+  conn.codec_mut().set_notify_handler(move |tg| {
+    let _ = tx.send(tg);
+  });
+  rx
 }
 
 
-#[derive(Debug)]
+/// Pass a telegram to the connection's notification handler.  Returns the
+/// telegram back if the connection doesn't have one.
+pub(crate) fn dispatch_notification<T>(
+  conn: &mut Framed<T, Codec>,
+  tg: Telegram
+) -> Result<(), Telegram> {
+  tracing::debug!(topic = tg.get_topic(), "notification");
+  conn.codec_mut().notify(tg)
+}
+
+
+#[derive(Clone, Debug)]
 pub struct WhoAmI {
   pub id: i64,
   pub name: String
@@ -199,7 +877,7 @@ pub struct WhoAmI {
 
 /// Return the current owner of a connection.
 pub async fn whoami<T: AsyncRead + AsyncWrite + Unpin>(
-  conn: &mut Framed<T, blather::Codec>
+  conn: &mut Framed<T, Codec>
 ) -> Result<WhoAmI, Error> {
   let tg = Telegram::new_topic("WhoAmI")?;
   let params = sendrecv(conn, &tg).await?;
@@ -208,4 +886,237 @@ pub async fn whoami<T: AsyncRead + AsyncWrite + Unpin>(
   Ok(WhoAmI { id, name })
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use tokio::net::TcpListener;
+
+  #[test]
+  fn parse_schemes() {
+    match "tcp://ddmw.local:4100".parse::<ProtAddr>().unwrap() {
+      ProtAddr::Tcp(sa) => assert_eq!(sa, "ddmw.local:4100"),
+      _ => panic!("Expected Tcp")
+    }
+    match "tcp://[::1]:4100".parse::<ProtAddr>().unwrap() {
+      ProtAddr::Tcp(sa) => assert_eq!(sa, "[::1]:4100"),
+      _ => panic!("Expected Tcp")
+    }
+    match "unix://ddmw.sock".parse::<ProtAddr>().unwrap() {
+      ProtAddr::Uds(p) => assert_eq!(p, PathBuf::from("ddmw.sock")),
+      _ => panic!("Expected Uds")
+    }
+    match "unix:///run/ddmw.sock".parse::<ProtAddr>().unwrap() {
+      ProtAddr::Uds(p) => assert_eq!(p, PathBuf::from("/run/ddmw.sock")),
+      _ => panic!("Expected Uds")
+    }
+    assert!("ftp://ddmw.local:21".parse::<ProtAddr>().is_err());
+    assert!("unix://".parse::<ProtAddr>().is_err());
+  }
+
+  #[test]
+  fn parse_fallback() {
+    match "/tmp/ddmw.sock".parse::<ProtAddr>().unwrap() {
+      ProtAddr::Uds(p) => assert_eq!(p, PathBuf::from("/tmp/ddmw.sock")),
+      _ => panic!("Expected Uds")
+    }
+    match "192.168.0.100:2000".parse::<ProtAddr>().unwrap() {
+      ProtAddr::Tcp(sa) => assert_eq!(sa, "192.168.0.100:2000"),
+      _ => panic!("Expected Tcp")
+    }
+  }
+
+  #[test]
+  fn parse_invalid() {
+    for addr in &[
+      "ddmw.sock",
+      "ddmw.local",
+      "ddmw.local:",
+      "ddmw.local:http",
+      "ddmw.local:0",
+      "ddmw.local:70000",
+      ":4100",
+      "::1:4100",
+      "[::1]",
+      "[::1:4100",
+      "[nope]:4100",
+      "300.1.1.1:4100",
+      "-ddmw.local:4100",
+      "ddmw_local:4100"
+    ] {
+      match addr.parse::<ProtAddr>() {
+        Err(Error::Parse(_)) => {}
+        _ => panic!("'{}' should not parse", addr)
+      }
+    }
+  }
+
+  #[test]
+  fn display_roundtrip() {
+    for addr in &["ddmw.local:4100", "/tmp/ddmw.sock", "unix://ddmw.sock"] {
+      let pa = addr.parse::<ProtAddr>().unwrap();
+      assert_eq!(&pa.to_string(), addr);
+    }
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn parse_linux() {
+    match "@ddmw".parse::<ProtAddr>().unwrap() {
+      ProtAddr::Abstract(name) => assert_eq!(name, "ddmw"),
+      _ => panic!("Expected Abstract")
+    }
+    match "fd://3".parse::<ProtAddr>().unwrap() {
+      ProtAddr::Fd(fd) => assert_eq!(fd, 3),
+      _ => panic!("Expected Fd")
+    }
+    assert!("@".parse::<ProtAddr>().is_err());
+    assert!("fd://".parse::<ProtAddr>().is_err());
+    assert!("fd://-1".parse::<ProtAddr>().is_err());
+    for addr in &["@ddmw", "fd://3"] {
+      let pa = addr.parse::<ProtAddr>().unwrap();
+      assert_eq!(&pa.to_string(), addr);
+    }
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn abstract_conn() {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixListener};
+
+    let name = format!("ddmw-test-{}", std::process::id());
+    let addr = SocketAddr::from_abstract_name(&name).unwrap();
+    let listener = UnixListener::bind_addr(&addr).unwrap();
+
+    let pa = format!("@{}", name).parse::<ProtAddr>().unwrap();
+    let _conn = connect(pa, None).await.unwrap();
+    listener.accept().unwrap();
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn fd_conn() {
+    use std::os::unix::io::AsRawFd;
+
+    let (a, _b) = std::os::unix::net::UnixStream::pair().unwrap();
+    let mut conn = connect(ProtAddr::Fd(a.as_raw_fd()), None).await.unwrap();
+    drop(a);
+    let tg = Telegram::new_topic("Ping").unwrap();
+    conn.send(&tg).await.unwrap();
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let a =
+      std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let _conn = connect(ProtAddr::Fd(a.as_raw_fd()), None).await.unwrap();
+
+    let file = std::fs::File::open("Cargo.toml").unwrap();
+    assert!(connect(ProtAddr::Fd(file.as_raw_fd()), None).await.is_err());
+
+    // Descriptors which are not open are rejected.
+    assert!(connect(ProtAddr::Fd(i32::MAX), None).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn interleaved_notification() {
+    use crate::testing::{Fault, MockServer};
+
+    let srv = MockServer::tcp().await.unwrap();
+    let link = Telegram::new_topic("LinkDown").unwrap();
+    srv.inject("WhoAmI", Fault::Notify(link.clone()));
+    srv.inject("WhoAmI", Fault::Notify(link));
+
+    let mut conn = connect(srv.protaddr(), None).await.unwrap();
+    let mut rx = notifications(&mut conn);
+    whoami(&mut conn).await.unwrap();
+    assert_eq!(rx.try_recv().unwrap().get_topic(), Some("LinkDown"));
+
+    conn.codec_mut().clear_notify_handler();
+    match whoami(&mut conn).await {
+      Err(Error::BadState(_)) => {}
+      _ => panic!("Expected bad state")
+    }
+  }
+
+  #[tokio::test]
+  async fn reply_timeout() {
+    // A server which accepts the connection but never replies.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let srv = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+    let mut opts = Options::default();
+    opts.timeouts.reply = Some(Duration::from_millis(100));
+    let pa = ProtAddr::Tcp(addr.to_string());
+    let mut conn = connect_with(pa, None, &opts).await.unwrap();
+    let _peer = srv.await.unwrap();
+
+    let tg = Telegram::new_topic("WhoAmI").unwrap();
+    match sendrecv(&mut conn, &tg).await {
+      Err(Error::Timeout(_)) => {}
+      _ => panic!("Expected timeout")
+    }
+
+    // The late reply could be taken for the reply to the next request.
+    assert!(conn.codec().is_poisoned());
+    match whoami(&mut conn).await {
+      Err(Error::Poisoned) => {}
+      _ => panic!("Expected poisoned connection")
+    }
+  }
+
+  #[tokio::test]
+  async fn write_timeout() {
+    // A peer which never reads.
+    let (client, _server) = tokio::io::duplex(64);
+    let mut codec = Codec::new();
+    codec.set_timeouts(Timeouts {
+      write: Some(Duration::from_millis(100)),
+      ..Timeouts::default()
+    });
+    let mut conn = Framed::new(client, codec);
+
+    let mut tg = Telegram::new_topic("Ping").unwrap();
+    tg.add_param("Data", "x".repeat(1024)).unwrap();
+    match sendrecv(&mut conn, &tg).await {
+      Err(Error::Timeout(_)) => {}
+      _ => panic!("Expected timeout")
+    }
+    assert!(conn.codec().is_poisoned());
+    match sendrecv(&mut conn, &tg).await {
+      Err(Error::Poisoned) => {}
+      _ => panic!("Expected poisoned connection")
+    }
+  }
+
+  #[tokio::test]
+  async fn socket_options() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let conf = TcpConf {
+      bind: Some("127.0.0.1:0".parse().unwrap()),
+      keepalive: Some(Duration::from_secs(30)),
+      nodelay: Some(true),
+      ..TcpConf::default()
+    };
+    let stream = open_tcp(&addr, &conf).await.unwrap();
+    let (_peer, peer_addr) = listener.accept().await.unwrap();
+    assert_eq!(stream.local_addr().unwrap(), peer_addr);
+    assert!(stream.nodelay().unwrap());
+    assert!(socket2::SockRef::from(&stream).keepalive().unwrap());
+
+    // A local address of the wrong family leaves nothing to connect to.
+    let conf = TcpConf {
+      bind: Some("[::1]:0".parse().unwrap()),
+      ..TcpConf::default()
+    };
+    match open_tcp(&addr, &conf).await {
+      Err(Error::BadParams(_)) => {}
+      _ => panic!("Expected bad parameters")
+    }
+  }
+}
+
 // vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
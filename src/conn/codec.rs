@@ -0,0 +1,361 @@
+//! Codec used by framed connections.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio_util::codec::{Decoder, Encoder};
+
+use bytes::{Bytes, BytesMut};
+
+use blather::{codec::Input, KVLines, Params, Telegram};
+
+use super::record::{Direction, Recorder};
+use super::stats::Stats;
+use super::Timeouts;
+
+use crate::err::Error;
+
+
+/// Closure called for each unsolicited telegram received on a connection.
+type NotifyCb = Box<dyn FnMut(Telegram) + Send>;
+
+
+/// A [`blather::Codec`] along with the per-connection settings and state that
+/// the functions operating on framed connections honor.
+///
+/// Connections established by [`connect()`](super::connect()) use this codec.
+/// Other transports can be used with this crate's functions by framing them
+/// using it:
+///
+/// ```
+/// use tokio_util::codec::Framed;
+/// use ddmw_client::conn::Codec;
+///
+/// let (client, _server) = tokio::io::duplex(4096);
+/// let conn = Framed::new(client, Codec::new());
+/// ```
+///
+/// Code which frames its transports using a `blather::Codec` can convert it
+/// using `into()`:
+///
+/// ```
+/// use tokio_util::codec::Framed;
+/// use ddmw_client::conn::Codec;
+///
+/// let (client, _server) = tokio::io::duplex(4096);
+/// let codec = blather::Codec::new();
+/// let conn: Framed<_, Codec> = Framed::new(client, codec.into());
+/// ```
+pub struct Codec {
+  inner: blather::Codec,
+  timeouts: Timeouts,
+
+  /// Notification handler.
+  notify: Option<NotifyCb>,
+
+  /// Traffic counters.
+  stats: Arc<Stats>,
+
+  /// Set when a timeout has left the connection in an unknown state.
+  poisoned: bool,
+
+  /// Traffic recorder, if the connection is being recorded.
+  rec: Option<Recorder>,
+
+  /// Set while message content, rather than telegrams, is being decoded.
+  content: bool
+}
+
+impl Default for Codec {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl From<blather::Codec> for Codec {
+  /// Wrap a `blather::Codec`, using the default settings.
+  fn from(inner: blather::Codec) -> Self {
+    Codec {
+      inner,
+      ..Codec::new()
+    }
+  }
+}
+
+impl Codec {
+  pub fn new() -> Self {
+    Codec {
+      inner: blather::Codec::new(),
+      timeouts: Timeouts::default(),
+      notify: None,
+      stats: Arc::new(Stats::new()),
+      poisoned: false,
+      rec: None,
+      content: false
+    }
+  }
+
+  /// Return the timeouts in effect for this connection.
+  pub fn timeouts(&self) -> &Timeouts {
+    &self.timeouts
+  }
+
+  /// Replace the timeouts in effect for this connection.
+  pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+    self.timeouts = timeouts;
+  }
+
+  /// Return the connection's traffic counters.
+  pub fn stats(&self) -> &Arc<Stats> {
+    &self.stats
+  }
+
+  /// Replace the connection's traffic counters, for instance to keep
+  /// counting in the same `Stats` after reconnecting.
+  pub fn set_stats(&mut self, stats: Arc<Stats>) {
+    self.stats = stats;
+  }
+
+  /// Register a closure which is called with each telegram the server sends
+  /// that is neither a reply to a request nor a message.
+  ///
+  /// Without a handler, such telegrams cause the request or message
+  /// reception they interrupted to fail with `Error::BadState`.
+  pub fn set_notify_handler<F>(&mut self, f: F)
+  where
+    F: FnMut(Telegram) + Send + 'static
+  {
+    self.notify = Some(Box::new(f));
+  }
+
+  /// Remove the notification handler.
+  pub fn clear_notify_handler(&mut self) {
+    self.notify = None;
+  }
+
+  /// Move the notification handler, if any, to `other`.
+  pub(crate) fn move_notify_handler(&mut self, other: &mut Codec) {
+    other.notify = self.notify.take();
+  }
+
+  /// Pass a notification to the handler.  Returns the telegram back if no
+  /// handler has been registered.
+  pub(crate) fn notify(&mut self, tg: Telegram) -> Result<(), Telegram> {
+    match &mut self.notify {
+      Some(f) => {
+        f(tg);
+        Ok(())
+      }
+      None => Err(tg)
+    }
+  }
+
+  /// Return `true` if a reply or write timeout has left the connection in an
+  /// unknown state.  Once this happens all further requests fail with
+  /// [`Error::Poisoned`], and the connection must be dropped.
+  pub fn is_poisoned(&self) -> bool {
+    self.poisoned
+  }
+
+  /// Mark the connection as unusable.
+  pub(crate) fn poison(&mut self) {
+    self.poisoned = true;
+  }
+
+  /// Fail with [`Error::Poisoned`] if the connection is unusable.
+  pub(crate) fn usable(&self) -> Result<(), Error> {
+    match self.poisoned {
+      true => Err(Error::Poisoned),
+      false => Ok(())
+    }
+  }
+
+  /// Start, or stop, recording the connection's traffic.
+  pub fn set_recorder(&mut self, rec: Option<Recorder>) {
+    self.rec = rec;
+  }
+
+  /// Stop recording the connection's traffic, and return the recorder.
+  pub fn take_recorder(&mut self) -> Option<Recorder> {
+    self.rec.take()
+  }
+
+  /// Pass a frame to the recorder, if there is one.  If recording fails it
+  /// is stopped, but the connection is left intact.
+  fn record(&mut self, dir: Direction, data: &[u8], tg: bool) {
+    if let Some(rec) = &self.rec {
+      if let Err(e) = rec.record(dir, data, tg) {
+        tracing::warn!("stopped recording connection; {}", e);
+        self.rec = None;
+      }
+    }
+  }
+
+  /// See [`blather::Codec::expect_chunks()`].
+  pub fn expect_chunks(&mut self, size: usize) {
+    self.inner.expect_chunks(size);
+    self.content = true;
+  }
+
+  /// See [`blather::Codec::expect_bytes()`].
+  pub fn expect_bytes(&mut self, size: usize) -> Result<(), blather::Error> {
+    self.inner.expect_bytes(size)?;
+    self.content = true;
+    Ok(())
+  }
+
+  /// See [`blather::Codec::expect_bytesmut()`].
+  pub fn expect_bytesmut(
+    &mut self,
+    size: usize
+  ) -> Result<(), blather::Error> {
+    self.inner.expect_bytesmut(size)?;
+    self.content = true;
+    Ok(())
+  }
+
+  /// See [`blather::Codec::expect_file()`].
+  pub fn expect_file<P: Into<PathBuf>>(
+    &mut self,
+    pathname: P,
+    size: usize
+  ) -> Result<(), blather::Error> {
+    self.inner.expect_file(pathname, size)?;
+    self.content = true;
+    Ok(())
+  }
+
+  /// See [`blather::Codec::expect_writer()`].
+  pub fn expect_writer<W: 'static + Write + Send + Sync>(
+    &mut self,
+    writer: W,
+    size: usize
+  ) -> Result<(), blather::Error> {
+    self.inner.expect_writer(writer, size)?;
+    self.content = true;
+    Ok(())
+  }
+
+  /// See [`blather::Codec::expect_params()`].
+  pub fn expect_params(&mut self) {
+    self.inner.expect_params();
+    self.content = true;
+  }
+
+  /// See [`blather::Codec::expect_kvlines()`].
+  pub fn expect_kvlines(&mut self) {
+    self.inner.expect_kvlines();
+    self.content = true;
+  }
+
+  /// See [`blather::Codec::skip()`].
+  pub fn skip(&mut self, size: usize) -> Result<(), blather::Error> {
+    self.inner.skip(size)?;
+    self.content = true;
+    Ok(())
+  }
+}
+
+impl Decoder for Codec {
+  type Item = Input;
+  type Error = blather::Error;
+
+  fn decode(
+    &mut self,
+    buf: &mut BytesMut
+  ) -> Result<Option<Input>, blather::Error> {
+    // The decoder consumes the frame from the front of the buffer.
+    let data = self.rec.as_ref().map(|_| buf.clone());
+    let res = self.inner.decode(buf);
+    if let Some(data) = data {
+      let used = data.len() - buf.len();
+      self.record(Direction::Recv, &data[..used], !self.content);
+    }
+
+    match &res {
+      Ok(Some(Input::Telegram(_))) => {}
+      Ok(Some(Input::Chunk(_, remain))) if *remain > 0 => {}
+      Ok(Some(_)) => self.content = false,
+      _ => {}
+    }
+    res
+  }
+}
+
+impl Encoder<&Telegram> for Codec {
+  type Error = blather::Error;
+
+  fn encode(
+    &mut self,
+    tg: &Telegram,
+    buf: &mut BytesMut
+  ) -> Result<(), blather::Error> {
+    let start = buf.len();
+    self.inner.encode(tg, buf)?;
+    self.record(Direction::Send, &buf[start..], true);
+    Ok(())
+  }
+}
+
+impl Encoder<&Params> for Codec {
+  type Error = blather::Error;
+
+  fn encode(
+    &mut self,
+    params: &Params,
+    buf: &mut BytesMut
+  ) -> Result<(), blather::Error> {
+    let start = buf.len();
+    self.inner.encode(params, buf)?;
+    self.record(Direction::Send, &buf[start..], false);
+    Ok(())
+  }
+}
+
+impl Encoder<&KVLines> for Codec {
+  type Error = blather::Error;
+
+  fn encode(
+    &mut self,
+    kvlines: &KVLines,
+    buf: &mut BytesMut
+  ) -> Result<(), blather::Error> {
+    let start = buf.len();
+    self.inner.encode(kvlines, buf)?;
+    self.record(Direction::Send, &buf[start..], false);
+    Ok(())
+  }
+}
+
+impl Encoder<Bytes> for Codec {
+  type Error = blather::Error;
+
+  fn encode(
+    &mut self,
+    data: Bytes,
+    buf: &mut BytesMut
+  ) -> Result<(), blather::Error> {
+    let start = buf.len();
+    self.inner.encode(data, buf)?;
+    self.record(Direction::Send, &buf[start..], false);
+    Ok(())
+  }
+}
+
+impl Encoder<&[u8]> for Codec {
+  type Error = blather::Error;
+
+  fn encode(
+    &mut self,
+    data: &[u8],
+    buf: &mut BytesMut
+  ) -> Result<(), blather::Error> {
+    let start = buf.len();
+    self.inner.encode(data, buf)?;
+    self.record(Direction::Send, &buf[start..], false);
+    Ok(())
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,282 @@
+//! Pipelined requests.
+//!
+//! A [`Pipeline`] keeps several requests in flight at once, instead of
+//! waiting for each reply before sending the next request.  Replies are
+//! paired with requests in the order they arrive, unless a request
+//! identifier parameter has been configured and the server echoes it back in
+//! its replies; then replies may arrive in any order.
+//!
+//! The number of requests in flight is bounded by the pipeline's window.
+//! Without a bound, a large batch could fill the socket buffers in both
+//! directions: the server would block writing replies the client isn't
+//! reading yet, while the client blocks writing requests the server isn't
+//! reading any more.
+
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use tokio_util::codec::Framed;
+
+use tracing::Instrument;
+
+use blather::{Params, Telegram};
+
+use crate::err::Error;
+use crate::trace;
+
+use super::record::Direction;
+use super::Codec;
+
+
+/// Default maximum number of requests in flight.
+const DEFAULT_WINDOW: usize = 16;
+
+
+/// A batch of requests to be sent back-to-back.
+#[derive(Clone, Debug)]
+pub struct Pipeline {
+  reqs: Vec<Telegram>,
+  reqid: Option<String>,
+  window: usize
+}
+
+impl Default for Pipeline {
+  fn default() -> Self {
+    Pipeline {
+      reqs: Vec::new(),
+      reqid: None,
+      window: DEFAULT_WINDOW
+    }
+  }
+}
+
+impl Pipeline {
+  /// Create an empty pipeline, which keeps at most 16 requests in flight.
+  pub fn new() -> Self {
+    Pipeline::default()
+  }
+
+  /// Add each request's index, in the parameter `key`, to the request.  If
+  /// the server includes the parameter in its reply, the reply is paired
+  /// with the request it belongs to regardless of the order in which replies
+  /// arrive.
+  pub fn set_reqid(&mut self, key: &str) -> &mut Self {
+    self.reqid = Some(key.to_string());
+    self
+  }
+
+  /// Set the maximum number of requests which may be awaiting replies at
+  /// any time.  A window of `0` is treated as `1`.
+  pub fn set_window(&mut self, n: usize) -> &mut Self {
+    self.window = n.max(1);
+    self
+  }
+
+  /// Queue a request, and return its index in the results.
+  pub fn push(&mut self, tg: Telegram) -> usize {
+    self.reqs.push(tg);
+    self.reqs.len() - 1
+  }
+
+  /// Return the number of queued requests.
+  pub fn len(&self) -> usize {
+    self.reqs.len()
+  }
+
+  /// Returns `true` if no requests have been queued.
+  pub fn is_empty(&self) -> bool {
+    self.reqs.is_empty()
+  }
+
+  /// Send all queued requests and wait for all their replies.  A new
+  /// request is sent each time a reply arrives, as long as the window allows
+  /// it.
+  ///
+  /// The returned vector holds one entry per request, in the order they
+  /// were queued.  A `Fail` reply yields an `Err(Error::ServerError)` entry
+  /// for its request without affecting the others.
+  ///
+  /// Errors affecting the connection as a whole, such as a disconnect or a
+  /// reply timeout, are returned as the outer `Err`, since the remaining
+  /// replies can no longer be trusted to arrive.
+  ///
+  /// Each reply's round-trip time, from when its request was written, is
+  /// added to the connection's [`Stats`](super::stats::Stats).
+  pub async fn run<T>(
+    &self,
+    conn: &mut Framed<T, Codec>
+  ) -> Result<Vec<Result<Params, Error>>, Error>
+  where
+    T: AsyncRead + AsyncWrite + Unpin
+  {
+    let span = tracing::debug_span!("pipeline", n = self.reqs.len());
+    self.exchange(conn).instrument(span).await
+  }
+
+  async fn exchange<T>(
+    &self,
+    conn: &mut Framed<T, Codec>
+  ) -> Result<Vec<Result<Params, Error>>, Error>
+  where
+    T: AsyncRead + AsyncWrite + Unpin
+  {
+    let n = self.reqs.len();
+    let mut results: Vec<Option<Result<Params, Error>>> =
+      (0..n).map(|_| None).collect();
+    let mut sent_at = Vec::with_capacity(n);
+    let mut sent = 0;
+
+    for rcvd in 0..n {
+      // Fill the window before waiting for the next reply.
+      let fill = sent < n && sent - rcvd < self.window;
+      while sent < n && sent - rcvd < self.window {
+        let mut tg = self.reqs[sent].clone();
+        if let Some(key) = &self.reqid {
+          tg.add_param(key, sent)?;
+        }
+        tracing::debug!(tg = %trace::tg(&tg), "send");
+        sent_at.push(Instant::now());
+        super::feed_frame(conn, &tg).await?;
+        conn.codec().stats().tg(Direction::Send);
+        sent += 1;
+      }
+      if fill {
+        super::flush_frames(conn).await?;
+      }
+
+      let res = match super::expect_okfail(conn).await {
+        Ok(params) => Ok(params),
+        Err(Error::ServerError(params)) => Err(params),
+        Err(e) => return Err(e)
+      };
+      let params = match &res {
+        Ok(p) | Err(p) => p
+      };
+
+      // Use the echoed request identifier if there is one, otherwise assume
+      // this is the reply to the oldest unanswered request.
+      let idx = self
+        .reqid
+        .as_ref()
+        .and_then(|key| params.get_param::<usize>(key).ok())
+        .filter(|idx| *idx < sent && results[*idx].is_none())
+        .or_else(|| results[..sent].iter().position(Option::is_none))
+        .ok_or_else(|| Error::bad_state("Reply without a request"))?;
+      conn.codec().stats().rtt(sent_at[idx].elapsed());
+      results[idx] = Some(res.map_err(Error::ServerError));
+    }
+
+    Ok(results.into_iter().flatten().collect())
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::time::Duration;
+
+  use futures::sink::SinkExt;
+
+  use tokio_stream::StreamExt;
+
+  use crate::conn;
+  use crate::testing::{Fault, MockServer};
+
+  #[tokio::test]
+  async fn in_order() {
+    let srv = MockServer::tcp().await.unwrap();
+    srv.inject("RdAcc", Fault::Fail("Nope".into()));
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+
+    let mut pl = Pipeline::new();
+    pl.push(Telegram::new_topic("WhoAmI").unwrap());
+    pl.push(Telegram::new_topic("RdAcc").unwrap());
+    pl.push(Telegram::new_topic("GetNodeInfo").unwrap());
+    let res = pl.run(&mut conn).await.unwrap();
+    assert_eq!(res.len(), 3);
+    assert!(res[0].as_ref().unwrap().have("Name"));
+    assert!(matches!(res[1], Err(Error::ServerError(_))));
+    assert!(res[2].as_ref().unwrap().have("ddmw.node"));
+
+    // Each reply, including the failed one, counts as a completed request.
+    let snap = conn.codec().stats().snapshot();
+    assert_eq!(snap.requests, 3);
+    assert!(snap.rtt_min <= snap.rtt_max);
+  }
+
+  #[tokio::test]
+  async fn out_of_order() {
+    let (client, server) = tokio::io::duplex(4096);
+
+    // A server which replies to three requests in reverse order.
+    tokio::spawn(async move {
+      let mut conn = Framed::new(server, blather::Codec::new());
+      let mut ids = Vec::new();
+      for _ in 0..3 {
+        if let Some(Ok(blather::codec::Input::Telegram(tg))) =
+          conn.next().await
+        {
+          ids.push(tg.get_str("_ReqId").unwrap().to_string());
+        }
+      }
+      for id in ids.into_iter().rev() {
+        let mut tg = Telegram::new_topic("Ok").unwrap();
+        tg.add_param("_ReqId", &id).unwrap();
+        tg.add_param("Echo", &id).unwrap();
+        conn.send(&tg).await.unwrap();
+      }
+    });
+
+    let mut conn = Framed::new(client, Codec::new());
+    let mut pl = Pipeline::new();
+    pl.set_reqid("_ReqId");
+    for _ in 0..3 {
+      pl.push(Telegram::new_topic("Ping").unwrap());
+    }
+    let res = pl.run(&mut conn).await.unwrap();
+    for (idx, r) in res.into_iter().enumerate() {
+      assert_eq!(r.unwrap().get_param::<usize>("Echo").unwrap(), idx);
+    }
+  }
+
+  #[tokio::test]
+  async fn bounded() {
+    // The buffers are too small to hold all the requests, or all the
+    // replies, at once.
+    let (client, server) = tokio::io::duplex(256);
+
+    // A server which replies to each request before reading the next one.
+    tokio::spawn(async move {
+      let mut conn = Framed::new(server, blather::Codec::new());
+      while let Some(Ok(blather::codec::Input::Telegram(tg))) =
+        conn.next().await
+      {
+        let mut reply = Telegram::new_topic("Ok").unwrap();
+        reply.add_param("Echo", tg.get_str("N").unwrap()).unwrap();
+        conn.send(&reply).await.unwrap();
+      }
+    });
+
+    let mut conn = Framed::new(client, Codec::new());
+    let mut pl = Pipeline::new();
+    pl.set_window(4);
+    for i in 0..100 {
+      let mut tg = Telegram::new_topic("Ping").unwrap();
+      tg.add_param("N", i).unwrap();
+      pl.push(tg);
+    }
+    let res = tokio::time::timeout(Duration::from_secs(5), pl.run(&mut conn))
+      .await
+      .expect("pipeline deadlocked")
+      .unwrap();
+    assert_eq!(res.len(), 100);
+    for (idx, r) in res.into_iter().enumerate() {
+      assert_eq!(r.unwrap().get_param::<usize>("Echo").unwrap(), idx);
+    }
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
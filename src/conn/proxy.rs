@@ -0,0 +1,384 @@
+//! Tunneling TCP/IP connections through HTTP proxies.
+//!
+//! If [`Options::proxy`](super::Options) has been set, [`ProtAddr::Tcp`]
+//! connections are established by asking an HTTP proxy to open a tunnel to
+//! the server using the `CONNECT` method.  The DDMW protocol is then spoken
+//! over the tunnel as usual.
+//!
+//! The proxy can either be specified explicitly, as a URL of the form
+//! `http://[user:pass@]host:port`, or be taken from the standard proxy
+//! environment variables; `https_proxy`, `all_proxy` and `http_proxy` (in
+//! that order, each in lower case first).  When the environment is used,
+//! servers matched by `no_proxy` are connected to directly.
+//!
+//! [`ProtAddr::Tcp`]: super::ProtAddr
+
+use std::fmt;
+use std::str::FromStr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::err::Error;
+
+use super::TcpConf;
+
+
+/// Environment variables which may hold the proxy URL, in order of
+/// precedence.
+const PROXY_VARS: &[&str] = &[
+  "https_proxy",
+  "HTTPS_PROXY",
+  "all_proxy",
+  "ALL_PROXY",
+  "http_proxy",
+  "HTTP_PROXY"
+];
+
+/// Upper limit of the size of the proxy's response header.
+const MAX_RESPONSE: usize = 8192;
+
+
+/// Which HTTP proxy, if any, to tunnel TCP/IP connections through.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Proxy {
+  /// Use the proxy specified by the environment, if any.
+  Env,
+
+  /// Use the specified proxy.
+  Http(HttpProxy)
+}
+
+impl Proxy {
+  /// Return the proxy to use for connecting to the server at `addr`, or
+  /// `None` if it should be connected to directly.
+  pub fn resolve(&self, addr: &str) -> Result<Option<HttpProxy>, Error> {
+    match self {
+      Proxy::Http(p) => Ok(Some(p.clone())),
+      Proxy::Env => from_env(addr)
+    }
+  }
+}
+
+impl FromStr for Proxy {
+  type Err = Error;
+
+  /// Parse `env`, or a proxy URL.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s == "env" {
+      return Ok(Proxy::Env);
+    }
+    Ok(Proxy::Http(s.parse()?))
+  }
+}
+
+
+/// An HTTP proxy.
+#[derive(Clone, PartialEq)]
+pub struct HttpProxy {
+  /// Proxy address, in `host:port` form.
+  pub addr: String,
+
+  /// User name and password for basic authentication against the proxy.
+  pub auth: Option<(String, String)>
+}
+
+impl fmt::Debug for HttpProxy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("HttpProxy")
+      .field("addr", &self.addr)
+      .field(
+        "auth",
+        &self.auth.as_ref().map(|(user, _)| (user, "<redacted>"))
+      )
+      .finish()
+  }
+}
+
+impl FromStr for HttpProxy {
+  type Err = Error;
+
+  /// Parse a proxy URL of the form `http://[user:pass@]host[:port]`.  The
+  /// scheme is optional, and the port defaults to 80.  The user name and
+  /// password may be percent-encoded.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let bad = |what: &str| Error::parse(format!("Proxy URL; {}", what));
+
+    let rest = match s.find("://") {
+      Some(idx) if s[..idx].eq_ignore_ascii_case("http") => &s[idx + 3..],
+      Some(_) => return Err(bad("only http proxies are supported")),
+      None => s
+    };
+    let rest = rest.trim_end_matches('/');
+
+    let (auth, hostport) = match rest.rfind('@') {
+      Some(idx) => {
+        let (user, pass) = match rest[..idx].split_once(':') {
+          Some((user, pass)) => (user, pass),
+          None => (&rest[..idx], "")
+        };
+        let user = pct_decode(user).ok_or_else(|| bad("bad user name"))?;
+        let pass = pct_decode(pass).ok_or_else(|| bad("bad password"))?;
+        (Some((user, pass)), &rest[idx + 1..])
+      }
+      None => (None, rest)
+    };
+    if hostport.is_empty() || hostport.contains('/') {
+      return Err(bad("bad host"));
+    }
+
+    // Add the default port unless there is one (taking care not to mistake
+    // an IPv6 address for one).
+    let addr = match hostport.rfind(':') {
+      Some(idx) if !hostport[idx..].contains(']') => {
+        hostport[idx + 1..]
+          .parse::<u16>()
+          .map_err(|_| bad("bad port"))?;
+        hostport.to_string()
+      }
+      _ => format!("{}:80", hostport)
+    };
+
+    Ok(HttpProxy { addr, auth })
+  }
+}
+
+
+/// Get the proxy to use for `addr` from the environment.
+fn from_env(addr: &str) -> Result<Option<HttpProxy>, Error> {
+  let url = PROXY_VARS
+    .iter()
+    .filter_map(|var| std::env::var(var).ok())
+    .find(|v| !v.is_empty());
+  let url = match url {
+    Some(url) => url,
+    None => return Ok(None)
+  };
+
+  let no_proxy = std::env::var("no_proxy")
+    .or_else(|_| std::env::var("NO_PROXY"))
+    .unwrap_or_default();
+  if bypass(&no_proxy, super::host_of(addr)) {
+    return Ok(None);
+  }
+
+  Ok(Some(url.parse()?))
+}
+
+
+/// Returns `true` if `host` is matched by the `no_proxy` list `list`.
+///
+/// Each comma-separated entry either is `*`, which matches all hosts, or a
+/// host name (optionally preceded by a `.`) which matches itself and all its
+/// subdomains.  Ports in entries are ignored.
+fn bypass(list: &str, host: &str) -> bool {
+  let host = host.to_ascii_lowercase();
+  list.split(',').map(str::trim).any(|ent| {
+    if ent == "*" {
+      return true;
+    }
+    let ent = super::host_of(ent).trim_start_matches('.');
+    if ent.is_empty() {
+      return false;
+    }
+    let ent = ent.to_ascii_lowercase();
+    host == ent || host.ends_with(&format!(".{}", ent))
+  })
+}
+
+
+/// Connect to `proxy`, and ask it to open a tunnel to `addr`.
+pub(crate) async fn connect(
+  proxy: &HttpProxy,
+  addr: &str,
+  tcp: &TcpConf
+) -> Result<TcpStream, Error> {
+  let mut stream = super::open_tcp(&proxy.addr, tcp).await?;
+
+  let mut req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", addr);
+  if let Some((user, pass)) = &proxy.auth {
+    let cred = base64(format!("{}:{}", user, pass).as_bytes());
+    req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", cred));
+  }
+  req.push_str("\r\n");
+  stream.write_all(req.as_bytes()).await?;
+
+  // Read the response header one byte at a time, so nothing which the server
+  // sends over the tunnel is consumed.
+  let mut resp = Vec::new();
+  while !resp.ends_with(b"\r\n\r\n") {
+    if resp.len() >= MAX_RESPONSE {
+      return Err(Error::bad_state("Proxy response header too large"));
+    }
+    match stream.read_u8().await {
+      Ok(b) => resp.push(b),
+      Err(_) => return Err(Error::Disconnected)
+    }
+  }
+
+  let status = String::from_utf8_lossy(&resp);
+  let status = status.lines().next().unwrap_or_default();
+  let code = status
+    .split_whitespace()
+    .nth(1)
+    .and_then(|c| c.parse::<u16>().ok());
+  match code {
+    Some(200..=299) if status.starts_with("HTTP/1.") => Ok(stream),
+    _ => Err(Error::bad_state(format!(
+      "Proxy refused tunnel to {}; {}",
+      addr, status
+    )))
+  }
+}
+
+
+fn pct_decode(s: &str) -> Option<String> {
+  let mut out = Vec::with_capacity(s.len());
+  let mut it = s.bytes();
+  while let Some(b) = it.next() {
+    if b != b'%' {
+      out.push(b);
+      continue;
+    }
+    let hex = [it.next()?, it.next()?];
+    let hex = std::str::from_utf8(&hex).ok()?;
+    out.push(u8::from_str_radix(hex, 16).ok()?);
+  }
+  String::from_utf8(out).ok()
+}
+
+
+fn base64(data: &[u8]) -> String {
+  const ALPHABET: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+  let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+  for chunk in data.chunks(3) {
+    let b = [
+      chunk[0],
+      *chunk.get(1).unwrap_or(&0),
+      *chunk.get(2).unwrap_or(&0)
+    ];
+    let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+    for i in 0..4 {
+      if i <= chunk.len() {
+        out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+      } else {
+        out.push('=');
+      }
+    }
+  }
+  out
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use tokio::io::AsyncBufReadExt;
+  use tokio::net::TcpListener;
+
+  use crate::conn::{self, Options};
+  use crate::testing::MockServer;
+
+  #[test]
+  fn parse() {
+    let p: HttpProxy = "http://proxy.local:3128".parse().unwrap();
+    assert_eq!(p.addr, "proxy.local:3128");
+    assert!(p.auth.is_none());
+
+    let p: HttpProxy = "HTTP://al%40ce:p%3Ass@[::1]/".parse().unwrap();
+    assert_eq!(p.addr, "[::1]:80");
+    assert_eq!(p.auth, Some(("al@ce".into(), "p:ss".into())));
+
+    assert!("https://proxy.local".parse::<HttpProxy>().is_err());
+    assert!("proxy.local:port".parse::<HttpProxy>().is_err());
+    assert_eq!("env".parse::<Proxy>().unwrap(), Proxy::Env);
+  }
+
+  #[test]
+  fn no_proxy() {
+    assert!(bypass("*", "ddmw.local"));
+    assert!(bypass("foo, .example.com:80", "ddmw.example.com"));
+    assert!(bypass("example.com", "EXAMPLE.com"));
+    assert!(!bypass("example.com", "badexample.com"));
+    assert!(!bypass("", "ddmw.local"));
+  }
+
+  #[test]
+  fn encode() {
+    assert_eq!(base64(b"alice:secret"), "YWxpY2U6c2VjcmV0");
+    assert_eq!(base64(b"ab"), "YWI=");
+    assert_eq!(base64(b"a"), "YQ==");
+  }
+
+  /// Start a minimal CONNECT proxy which requires the credentials
+  /// `user:pass`.
+  async fn proxy() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+      loop {
+        let (sock, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+          let mut sock = tokio::io::BufReader::new(sock);
+          let mut target = None;
+          let mut authed = false;
+          loop {
+            let mut line = String::new();
+            sock.read_line(&mut line).await.unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+              break;
+            }
+            if let Some(rest) = line.strip_prefix("CONNECT ") {
+              target = rest.split(' ').next().map(str::to_string);
+            }
+            if line == "Proxy-Authorization: Basic dXNlcjpwYXNz" {
+              authed = true;
+            }
+          }
+          let mut sock = sock.into_inner();
+          if !authed {
+            let resp = "HTTP/1.1 407 Proxy Authentication Required\r\n\r\n";
+            let _ = sock.write_all(resp.as_bytes()).await;
+            return;
+          }
+          let mut upstream =
+            TcpStream::connect(target.unwrap()).await.unwrap();
+          sock
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+          let _ =
+            tokio::io::copy_bidirectional(&mut sock, &mut upstream).await;
+        });
+      }
+    });
+    addr
+  }
+
+  #[tokio::test]
+  async fn tunnel() {
+    let srv = MockServer::tcp().await.unwrap();
+    let addr = proxy().await;
+
+    let opts = Options {
+      proxy: Some(format!("http://user:pass@{}", addr).parse().unwrap()),
+      ..Options::default()
+    };
+    let mut conn = conn::connect_with(srv.protaddr(), None, &opts)
+      .await
+      .unwrap();
+    assert_eq!(conn::whoami(&mut conn).await.unwrap().id, 0);
+
+    let opts = Options {
+      proxy: Some(format!("http://user:wrong@{}", addr).parse().unwrap()),
+      ..Options::default()
+    };
+    let res = conn::connect_with(srv.protaddr(), None, &opts).await;
+    assert!(matches!(res, Err(Error::BadState(_))));
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,482 @@
+//! Reestablish lost connections.
+//!
+//! A [`Reconnector`] remembers everything that is needed to bring a
+//! connection back to the state it was in before it was lost; the address to
+//! connect to, the authentication context and the message channel
+//! subscription (if any).  Failed attempts are retried using exponential
+//! backoff with jitter.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::auth::Auth;
+use crate::err::Error;
+use crate::msg::recv::{self, SubInfo};
+
+use super::{Frm, Options, ProtAddr};
+
+
+/// Exponential backoff policy used between reconnection attempts.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+  /// Delay before the first retry.
+  pub initial: Duration,
+
+  /// Upper bound of the delay between attempts.
+  pub max: Duration,
+
+  /// Factor the delay is multiplied with after each failed attempt.
+  pub factor: u32,
+
+  /// Randomize each delay to somewhere between half of, and the entire,
+  /// computed delay.  This avoids having many clients reconnect in lockstep
+  /// after a server restart.
+  pub jitter: bool,
+
+  /// Give up after this many failed attempts.  `None` means keep trying
+  /// forever.
+  pub max_attempts: Option<u32>
+}
+
+impl Default for Backoff {
+  fn default() -> Self {
+    Backoff {
+      initial: Duration::from_millis(500),
+      max: Duration::from_secs(60),
+      factor: 2,
+      jitter: true,
+      max_attempts: None
+    }
+  }
+}
+
+impl Backoff {
+  /// Return the delay to wait after the failed attempt number `attempt`
+  /// (starting at 1), without jitter applied.
+  pub fn delay(&self, attempt: u32) -> Duration {
+    let mut delay = self.initial;
+    for _ in 1..attempt {
+      if delay >= self.max {
+        break;
+      }
+      delay = delay.saturating_mul(self.factor);
+    }
+    delay.min(self.max)
+  }
+
+  /// Return the delay to wait after the failed attempt number `attempt`, with
+  /// jitter applied if it has been enabled.
+  fn jittered(&self, attempt: u32) -> Duration {
+    let delay = self.delay(attempt);
+    if self.jitter && !delay.is_zero() {
+      let half = delay / 2;
+      half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    } else {
+      delay
+    }
+  }
+}
+
+
+/// Progress of a connection attempt, passed to the application's attempt
+/// callback.  Attempts are numbered starting at 1.
+pub enum Attempt<'a> {
+  /// Attempt number `num` is about to be made.
+  Start { num: u32 },
+
+  /// Attempt number `num` succeeded; the connection has been established,
+  /// authenticated and subscribed.
+  Success { num: u32 },
+
+  /// Attempt number `num` failed with the error `err`.
+  Failure {
+    num: u32,
+    err: &'a Error,
+
+    /// How long until the next attempt is made.  `None` means the
+    /// reconnector has given up.
+    retry_in: Option<Duration>
+  }
+}
+
+
+/// Closure called for each connection attempt.
+type AttemptCb = Box<dyn FnMut(&Attempt) + Send>;
+
+
+/// Connection factory which redials, reauthenticates and resubscribes.
+pub struct Reconnector {
+  pa: ProtAddr,
+  opts: Options,
+  auth: Option<Auth>,
+  sub: Option<SubInfo>,
+  retry_sub_fail: bool,
+  backoff: Backoff,
+  cb: Option<AttemptCb>
+}
+
+impl Reconnector {
+  /// Create a reconnector for the client interface at `pa`, using the
+  /// default [`Backoff`] policy.
+  pub fn new(pa: ProtAddr) -> Self {
+    Reconnector {
+      pa,
+      opts: Options::default(),
+      auth: None,
+      sub: None,
+      retry_sub_fail: false,
+      backoff: Backoff::default(),
+      cb: None
+    }
+  }
+
+  /// Set the connection options used for each new connection.
+  pub fn set_opts(&mut self, opts: Options) -> &mut Self {
+    self.opts = opts;
+    self
+  }
+
+  /// Authenticate each new connection using `auth`.
+  pub fn set_auth(&mut self, auth: Option<Auth>) -> &mut Self {
+    self.auth = auth;
+    self
+  }
+
+  /// Subscribe each new connection to an application message channel.
+  pub fn set_sub(&mut self, sub: Option<SubInfo>) -> &mut Self {
+    self.sub = sub;
+    self
+  }
+
+  /// Retry if the server rejects the subscription request.
+  ///
+  /// By default a rejected subscription is a permanent error.  Failing to
+  /// authenticate is always a permanent error.
+  pub fn set_retry_sub_fail(&mut self, retry: bool) -> &mut Self {
+    self.retry_sub_fail = retry;
+    self
+  }
+
+  /// Set the backoff policy used between failed attempts.
+  pub fn set_backoff(&mut self, backoff: Backoff) -> &mut Self {
+    self.backoff = backoff;
+    self
+  }
+
+  /// Register a closure which is called when each connection attempt starts,
+  /// and when it succeeds or fails.
+  pub fn set_callback<F>(&mut self, f: F) -> &mut Self
+  where
+    F: FnMut(&Attempt) + Send + 'static
+  {
+    self.cb = Some(Box::new(f));
+    self
+  }
+
+  /// Return the address of the client interface to connect to.
+  pub fn protaddr(&self) -> &ProtAddr {
+    &self.pa
+  }
+
+  /// Return the connection options used for each new connection.
+  pub fn opts(&self) -> &Options {
+    &self.opts
+  }
+
+  /// Return a mutable reference to the connection options used for each new
+  /// connection.
+  pub fn opts_mut(&mut self) -> &mut Options {
+    &mut self.opts
+  }
+
+  /// Return the authentication context used for each new connection.
+  pub fn auth(&self) -> Option<&Auth> {
+    self.auth.as_ref()
+  }
+
+  /// Return the application message channel each new connection is
+  /// subscribed to.
+  pub fn sub(&self) -> Option<&SubInfo> {
+    self.sub.as_ref()
+  }
+
+  /// Return the backoff policy used between failed attempts.
+  pub fn backoff(&self) -> &Backoff {
+    &self.backoff
+  }
+
+
+  /// Make a single attempt to connect, authenticate and subscribe.
+  pub async fn connect_once(&self) -> Result<Frm, Error> {
+    self.attempt().await.map_err(|(err, _)| err)
+  }
+
+  /// Make a single attempt to connect, authenticate and subscribe.  On
+  /// failure, also return whether the error is worth retrying.
+  async fn attempt(&self) -> Result<Frm, (Error, bool)> {
+    let mut conn =
+      super::connect_with(&self.pa, self.auth.as_ref(), &self.opts)
+        .await
+        .map_err(|e| {
+          let transient = is_transient(&e);
+          (e, transient)
+        })?;
+    if let Some(sub) = &self.sub {
+      if let Err(e) = recv::subscribe(&mut conn, sub.clone()).await {
+        let transient = is_transient(&e)
+          || (self.retry_sub_fail && matches!(e, Error::ServerError(_)));
+        return Err((e, transient));
+      }
+    }
+    Ok(conn)
+  }
+
+  /// Keep attempting to connect, authenticate and subscribe until it
+  /// succeeds, a non-transient error is encountered, or the backoff policy's
+  /// maximum number of attempts has been reached.
+  ///
+  /// The error of the final attempt is returned on failure.
+  pub async fn connect(&mut self) -> Result<Frm, Error> {
+    let mut num = 0;
+    loop {
+      num += 1;
+      self.report(&Attempt::Start { num });
+      let (err, transient) = match self.attempt().await {
+        Ok(conn) => {
+          self.report(&Attempt::Success { num });
+          return Ok(conn);
+        }
+        Err(e) => e
+      };
+
+      let giveup = !transient
+        || matches!(self.backoff.max_attempts, Some(max) if num >= max);
+      let retry_in = if giveup {
+        None
+      } else {
+        Some(self.backoff.jittered(num))
+      };
+
+      self.report(&Attempt::Failure {
+        num,
+        err: &err,
+        retry_in
+      });
+
+      match retry_in {
+        Some(delay) => tokio::time::sleep(delay).await,
+        None => return Err(err)
+      }
+    }
+  }
+
+  fn report(&mut self, attempt: &Attempt) {
+    if let Some(ref mut cb) = self.cb {
+      cb(attempt);
+    }
+  }
+}
+
+
+/// Return `true` if an error could conceivably go away by trying again.
+///
+/// Only errors caused by the connection itself, including a stalled
+/// connection timing out, are transient.  Protocol errors, such as unexpected
+/// or malformed replies, are not, since a new connection to the same server
+/// is likely to run into them again.
+pub(crate) fn is_transient(err: &Error) -> bool {
+  matches!(
+    err,
+    Error::IO(_) | Error::Disconnected | Error::Poisoned | Error::Timeout(_)
+  )
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::{Arc, Mutex};
+
+  use crate::conn;
+  use crate::msg::recv::{MsgInfo, StoreType, SubCh};
+  use crate::testing::{Fault, MockMsg, MockServer};
+
+  #[test]
+  fn exponential_delay() {
+    let b = Backoff {
+      initial: Duration::from_secs(1),
+      max: Duration::from_secs(10),
+      factor: 2,
+      jitter: false,
+      max_attempts: None
+    };
+    assert_eq!(b.delay(1), Duration::from_secs(1));
+    assert_eq!(b.delay(2), Duration::from_secs(2));
+    assert_eq!(b.delay(4), Duration::from_secs(8));
+    assert_eq!(b.delay(5), Duration::from_secs(10));
+    assert_eq!(b.delay(1000), Duration::from_secs(10));
+  }
+
+  #[test]
+  fn jitter_within_bounds() {
+    let b = Backoff {
+      initial: Duration::from_secs(4),
+      ..Backoff::default()
+    };
+    for _ in 0..100 {
+      let d = b.jittered(1);
+      assert!(d >= Duration::from_secs(2) && d <= Duration::from_secs(4));
+    }
+  }
+
+  #[test]
+  fn transient() {
+    assert!(is_transient(&Error::Disconnected));
+    assert!(is_transient(&Error::Poisoned));
+    assert!(is_transient(&Error::Timeout("reply".into())));
+    assert!(is_transient(&Error::IO("reset".into())));
+    assert!(!is_transient(&Error::bad_state("unexpected reply")));
+    assert!(!is_transient(&Error::Blather("bad telegram".into())));
+    assert!(!is_transient(&Error::invalid_cred("rejected")));
+  }
+
+  #[tokio::test]
+  async fn reconnect() {
+    let srv = MockServer::tcp().await.unwrap();
+    let id = srv.add_account("alice", "secret", &[]);
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut rc = Reconnector::new(srv.protaddr());
+    rc.set_auth(Some(Auth {
+      name: Some("alice".into()),
+      pass: Some("secret".into()),
+      ..Auth::default()
+    }))
+    .set_sub(Some(SubInfo { ch: SubCh::Num(7) }))
+    .set_backoff(Backoff {
+      initial: Duration::from_millis(1),
+      ..Backoff::default()
+    })
+    .set_callback({
+      let events = Arc::clone(&events);
+      move |a| {
+        let ev = match a {
+          Attempt::Start { num } => format!("start {}", num),
+          Attempt::Success { num } => format!("success {}", num),
+          Attempt::Failure { num, .. } => format!("failure {}", num)
+        };
+        events.lock().unwrap().push(ev);
+      }
+    });
+
+    let store = |_: &MsgInfo| Ok((StoreType::None, StoreType::None));
+    let mut conn = rc.connect().await.unwrap();
+    srv.push_msg(MockMsg {
+      ch: "7".into(),
+      cmd: 1,
+      ..MockMsg::default()
+    });
+    assert_eq!(recv::recv(&mut conn, store).await.unwrap().cmd, 1);
+
+    // Lose the connection, and fail the first attempt to reauthenticate.
+    srv.push_disconnect();
+    assert!(recv::recv(&mut conn, store).await.is_err());
+    srv.inject("Auth", Fault::Disconnect);
+    events.lock().unwrap().clear();
+
+    let mut conn = rc.connect().await.unwrap();
+    assert_eq!(
+      *events.lock().unwrap(),
+      ["start 1", "failure 1", "start 2", "success 2"]
+    );
+
+    // The new connection is authenticated and subscribed.
+    srv.push_msg(MockMsg {
+      ch: "7".into(),
+      cmd: 2,
+      ..MockMsg::default()
+    });
+    assert_eq!(recv::recv(&mut conn, store).await.unwrap().cmd, 2);
+    assert_eq!(conn::whoami(&mut conn).await.unwrap().id, id);
+  }
+
+  #[tokio::test]
+  async fn stalled_reply() {
+    let srv = MockServer::tcp().await.unwrap();
+    srv.inject("Sub", Fault::Stall);
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut opts = Options::default();
+    opts.timeouts.reply = Some(Duration::from_millis(100));
+    let mut rc = Reconnector::new(srv.protaddr());
+    rc.set_opts(opts)
+      .set_sub(Some(SubInfo { ch: SubCh::Num(7) }))
+      .set_backoff(Backoff {
+        initial: Duration::from_millis(1),
+        ..Backoff::default()
+      })
+      .set_callback({
+        let events = Arc::clone(&events);
+        move |a| {
+          if let Attempt::Failure { err, .. } = a {
+            events.lock().unwrap().push(err.to_string());
+          }
+        }
+      });
+
+    // The first subscription request times out, and the second attempt
+    // succeeds.
+    rc.connect().await.unwrap();
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 1);
+    assert!(events[0].starts_with("Timed out"));
+  }
+
+  #[tokio::test]
+  async fn permanent_error() {
+    let srv = MockServer::tcp().await.unwrap();
+    srv.inject("Sub", Fault::Fail("Nope".into()));
+
+    let mut rc = Reconnector::new(srv.protaddr());
+    rc.set_sub(Some(SubInfo { ch: SubCh::Num(7) }));
+    let res = rc.connect().await;
+    assert!(matches!(res, Err(Error::ServerError(_))));
+  }
+
+  #[tokio::test]
+  async fn retry_sub_fail() {
+    let srv = MockServer::tcp().await.unwrap();
+    let id = srv.add_account("alice", "secret", &[]);
+    srv.inject("Sub", Fault::Fail("Not yet".into()));
+
+    let mut rc = Reconnector::new(srv.protaddr());
+    rc.set_auth(Some(Auth {
+      name: Some("alice".into()),
+      pass: Some("secret".into()),
+      ..Auth::default()
+    }))
+    .set_sub(Some(SubInfo { ch: SubCh::Num(7) }))
+    .set_retry_sub_fail(true)
+    .set_backoff(Backoff {
+      initial: Duration::from_millis(1),
+      ..Backoff::default()
+    });
+
+    // A rejected subscription is retried.
+    let mut conn = rc.connect().await.unwrap();
+    assert_eq!(conn::whoami(&mut conn).await.unwrap().id, id);
+
+    // Rejected credentials are not.
+    rc.set_auth(Some(Auth {
+      name: Some("alice".into()),
+      pass: Some("wrong".into()),
+      ..Auth::default()
+    }));
+    let res = rc.connect().await;
+    assert!(matches!(res, Err(Error::ServerError(_))), "{:?}", res.err());
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,467 @@
+//! Recording and replaying connection traffic.
+//!
+//! A [`Recorder`] attached to a connection's [`Codec`](super::Codec) writes
+//! every frame sent and received on it, along with its direction and the time
+//! since the recording started, to a capture file.  The capture includes
+//! telegrams as well as message metadata and payloads.  The values of
+//! credential parameters (such as `Pass` and `Tkn`) in telegrams are masked
+//! out in the capture; message content is recorded as-is.
+//!
+//! A capture can later be loaded using [`load()`] and played back by
+//! [`replay()`], which acts as a fake server; it sends the data the server
+//! sent, and consumes the data the client sent.  This makes it possible to
+//! run [`msg::recv()`](crate::msg::recv()) or
+//! [`msg::send()`](crate::msg::send()) against a recorded exchange.
+//!
+//! The capture format is line based; each line contains the number of
+//! microseconds since the recording started, a direction marker (`>` for
+//! data sent by the client, `<` for data received by it), and the data with
+//! non-printable bytes escaped.  Lines starting with `#` are ignored.
+//!
+//! # Example
+//! Replay a capture against `msg::recv()`.
+//!
+//! ```no_run
+//! use tokio_util::codec::Framed;
+//! use ddmw_client::{conn::{self, record}, msg::{self, recv::StoreType}};
+//!
+//! async fn rerun() {
+//!   let records = record::load("incident.cap").unwrap();
+//!   let (client, server) = tokio::io::duplex(64 * 1024);
+//!   tokio::spawn(async move { record::replay(server, &records, false).await });
+//!
+//!   let mut conn = Framed::new(client, conn::Codec::new());
+//!   let msg = msg::recv(&mut conn, |_| Ok((StoreType::Bytes, StoreType::Bytes)))
+//!     .await
+//!     .unwrap();
+//! }
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinHandle;
+
+use crate::err::Error;
+use crate::trace::SECRET_KEYS;
+use crate::utils;
+
+
+/// Direction of a recorded chunk of data, as seen by the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+  /// Sent from the client to the server.
+  Send,
+
+  /// Sent from the server to the client.
+  Recv
+}
+
+
+/// A single recorded chunk of data.
+#[derive(Clone, Debug)]
+pub struct Record {
+  /// Time since the recording was started.
+  pub at: Duration,
+  pub dir: Direction,
+  pub data: Vec<u8>
+}
+
+
+/// Writes the traffic of a connection to a capture file.
+///
+/// The file is written by a task on tokio's blocking thread pool, so
+/// recording doesn't hold up the connection.
+pub struct Recorder {
+  tx: mpsc::Sender<Record>,
+  start: Instant,
+  writer: JoinHandle<io::Result<()>>
+}
+
+impl Recorder {
+  /// Create a capture file, readable and writable only by its owner.  Must
+  /// be called from within a tokio runtime.
+  ///
+  /// If the file already exists the recording is appended to it, so that
+  /// connections re-established using the same [`Options`](super::Options)
+  /// don't wipe out the capture.  Each recording starts with a comment line,
+  /// and has its own time base.
+  pub fn create<P: AsRef<Path>>(fname: P) -> Result<Self, Error> {
+    let mut f = utils::create_private(fname.as_ref(), false)?;
+    f.seek(SeekFrom::End(0))?;
+    let mut out = BufWriter::new(f);
+    writeln!(out, "# ddmw-client capture")?;
+
+    let (tx, rx) = mpsc::channel();
+    let writer = tokio::task::spawn_blocking(move || {
+      let res = write_records(out, rx);
+      if let Err(e) = &res {
+        tracing::warn!("stopped recording connection; {}", e);
+      }
+      res
+    });
+    Ok(Recorder {
+      tx,
+      start: Instant::now(),
+      writer
+    })
+  }
+
+  /// Queue a frame for the capture.  If `tg` is `true` the frame holds
+  /// telegram lines, and the values of credential parameters are masked.
+  ///
+  /// Fails if the capture file can no longer be written.
+  pub(crate) fn record(
+    &self,
+    dir: Direction,
+    data: &[u8],
+    tg: bool
+  ) -> io::Result<()> {
+    if data.is_empty() {
+      return Ok(());
+    }
+    let mut data = data.to_vec();
+    if tg {
+      redact(&mut data);
+    }
+    let rec = Record {
+      at: self.start.elapsed(),
+      dir,
+      data
+    };
+    self.tx.send(rec).map_err(|_| {
+      io::Error::new(io::ErrorKind::BrokenPipe, "capture writer has stopped")
+    })
+  }
+
+  /// Stop recording, and wait for everything recorded so far to be written
+  /// to the capture file.
+  pub async fn finish(self) -> Result<(), Error> {
+    drop(self.tx);
+    self.writer.await.map_err(io::Error::other)??;
+    Ok(())
+  }
+}
+
+
+/// Write records to the capture file until the recorder is dropped.
+fn write_records(
+  mut out: BufWriter<File>,
+  rx: mpsc::Receiver<Record>
+) -> io::Result<()> {
+  let write = |out: &mut BufWriter<File>, rec: Record| {
+    let marker = match rec.dir {
+      Direction::Send => '>',
+      Direction::Recv => '<'
+    };
+    writeln!(
+      out,
+      "{} {} {}",
+      rec.at.as_micros(),
+      marker,
+      escape(&rec.data)
+    )
+  };
+  while let Ok(rec) = rx.recv() {
+    write(&mut out, rec)?;
+    // Write whatever else has been queued up before flushing.
+    while let Ok(rec) = rx.try_recv() {
+      write(&mut out, rec)?;
+    }
+    out.flush()?;
+  }
+  Ok(())
+}
+
+
+/// Mask the values of credential parameters in complete telegram lines.
+///
+/// Values are overwritten rather than removed so that the amount of data is
+/// preserved.
+fn redact(data: &mut [u8]) {
+  for line in data.split_mut(|b| *b == b'\n') {
+    for key in SECRET_KEYS {
+      let key = key.as_bytes();
+      if line.len() > key.len()
+        && line.starts_with(key)
+        && line[key.len()] == b' '
+      {
+        line[key.len() + 1..].fill(b'*');
+      }
+    }
+  }
+}
+
+
+/// Load a capture file.
+pub fn load<P: AsRef<Path>>(fname: P) -> Result<Vec<Record>, Error> {
+  let f = BufReader::new(File::open(fname)?);
+  let mut records = Vec::new();
+  for (n, line) in f.lines().enumerate() {
+    let line = line?;
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let bad = || Error::parse(format!("Malformed capture line {}", n + 1));
+
+    let mut it = line.splitn(3, ' ');
+    let at = it
+      .next()
+      .and_then(|s| s.parse::<u64>().ok())
+      .ok_or_else(bad)?;
+    let dir = match it.next() {
+      Some(">") => Direction::Send,
+      Some("<") => Direction::Recv,
+      _ => return Err(bad())
+    };
+    let data = unescape(it.next().ok_or_else(bad)?).ok_or_else(bad)?;
+
+    records.push(Record {
+      at: Duration::from_micros(at),
+      dir,
+      data
+    });
+  }
+  Ok(records)
+}
+
+
+/// Act as the server side of a recorded exchange.
+///
+/// Data the server sent is written to `io`, and for data the client sent the
+/// same number of bytes are read from `io` and discarded.  If `timing` is
+/// `true` the original timing of the server's data is reproduced.
+///
+/// Returns `Err(Error::Disconnected)` if the client closes the connection
+/// before the capture has been played back in full.
+pub async fn replay<T>(
+  mut io: T,
+  records: &[Record],
+  timing: bool
+) -> Result<(), Error>
+where
+  T: AsyncRead + AsyncWrite + Unpin
+{
+  let start = tokio::time::Instant::now();
+  let mut buf = Vec::new();
+  for rec in records {
+    match rec.dir {
+      Direction::Send => {
+        buf.resize(rec.data.len(), 0);
+        if io.read_exact(&mut buf).await.is_err() {
+          return Err(Error::Disconnected);
+        }
+      }
+      Direction::Recv => {
+        if timing {
+          tokio::time::sleep_until(start + rec.at).await;
+        }
+        io.write_all(&rec.data).await?;
+      }
+    }
+  }
+  io.shutdown().await?;
+  Ok(())
+}
+
+
+fn escape(data: &[u8]) -> String {
+  let mut s = String::with_capacity(data.len());
+  for &b in data {
+    match b {
+      b'\\' => s.push_str("\\\\"),
+      b'\n' => s.push_str("\\n"),
+      0x20..=0x7e => s.push(b as char),
+      _ => s.push_str(&format!("\\x{:02x}", b))
+    }
+  }
+  s
+}
+
+fn unescape(s: &str) -> Option<Vec<u8>> {
+  let mut out = Vec::with_capacity(s.len());
+  let mut it = s.bytes();
+  while let Some(b) = it.next() {
+    if b != b'\\' {
+      out.push(b);
+      continue;
+    }
+    match it.next()? {
+      b'\\' => out.push(b'\\'),
+      b'n' => out.push(b'\n'),
+      b'x' => {
+        let hex = [it.next()?, it.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        out.push(u8::from_str_radix(hex, 16).ok()?);
+      }
+      _ => return None
+    }
+  }
+  Some(out)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use futures::sink::SinkExt;
+
+  use tokio_stream::StreamExt;
+
+  use tokio_util::codec::Framed;
+
+  use blather::{codec::Input, Telegram};
+
+  use crate::conn::{self, reconn::Reconnector, Options};
+  use crate::msg::recv::{self, Storage, StoreType, SubCh, SubInfo};
+  use crate::testing::{MockMsg, MockServer};
+
+  #[test]
+  fn escaping() {
+    let data = b"Msg\nLen 3\n\n\x00\\\xff".to_vec();
+    assert_eq!(unescape(&escape(&data)).unwrap(), data);
+    assert!(unescape("\\q").is_none());
+    assert!(unescape("\\x4").is_none());
+  }
+
+  #[test]
+  fn redact_params() {
+    let mut data =
+      b"Auth\nAccName Passy\nPass secret\nTkn x\nTknx y\n\n".to_vec();
+    redact(&mut data);
+    assert_eq!(data, b"Auth\nAccName Passy\nPass ******\nTkn *\nTknx y\n\n");
+  }
+
+  #[tokio::test]
+  async fn redact_telegrams_only() {
+    let fname = std::env::temp_dir()
+      .join(format!("ddmw-redact-{}.cap", std::process::id()));
+    let (client, server) = tokio::io::duplex(4096);
+    let mut codec = conn::Codec::new();
+    codec.set_recorder(Some(Recorder::create(&fname).unwrap()));
+    let mut conn = Framed::new(client, codec);
+    let mut srv = Framed::new(server, blather::Codec::new());
+
+    // Telegrams carrying credentials, each followed by content which happens
+    // to look like a credential parameter.
+    let mut tg = Telegram::new_topic("Auth").unwrap();
+    tg.add_param("Pass", "secret").unwrap();
+    conn.send(&tg).await.unwrap();
+    conn.send(&b"Pass word\n"[..]).await.unwrap();
+
+    let mut tg = Telegram::new_topic("Ok").unwrap();
+    tg.add_param("Tkn", "abc").unwrap();
+    srv.send(&tg).await.unwrap();
+    srv.send(&b"Tkn 123\n"[..]).await.unwrap();
+    assert!(matches!(conn.next().await, Some(Ok(Input::Telegram(_)))));
+    conn.codec_mut().expect_bytes(8).unwrap();
+    assert!(matches!(conn.next().await, Some(Ok(Input::Bytes(_)))));
+
+    let rec = conn.codec_mut().take_recorder().unwrap();
+    rec.finish().await.unwrap();
+
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = std::fs::metadata(&fname).unwrap().permissions().mode();
+      assert_eq!(mode & 0o777, 0o600);
+    }
+
+    let records = load(&fname).unwrap();
+    let _ = std::fs::remove_file(&fname);
+    let data = |dir| {
+      records
+        .iter()
+        .filter(|r| r.dir == dir)
+        .flat_map(|r| r.data.clone())
+        .collect::<Vec<u8>>()
+    };
+    assert_eq!(data(Direction::Send), b"Auth\nPass ******\n\nPass word\n");
+    assert_eq!(data(Direction::Recv), b"Ok\nTkn ***\n\nTkn 123\n");
+  }
+
+  #[tokio::test]
+  async fn append_on_reconnect() {
+    let fname = std::env::temp_dir()
+      .join(format!("ddmw-append-{}.cap", std::process::id()));
+
+    // Each connection established by the reconnector subscribes, and both
+    // subscriptions end up in the capture.
+    let srv = MockServer::tcp().await.unwrap();
+    let mut rc = Reconnector::new(srv.protaddr());
+    rc.set_opts(Options {
+      record: Some(fname.clone()),
+      ..Options::default()
+    })
+    .set_sub(Some(SubInfo { ch: SubCh::Num(1) }));
+    for _ in 0..2 {
+      let mut conn = rc.connect().await.unwrap();
+      let rec = conn.codec_mut().take_recorder().unwrap();
+      rec.finish().await.unwrap();
+    }
+
+    let records = load(&fname).unwrap();
+    let _ = std::fs::remove_file(&fname);
+    let subs = records
+      .iter()
+      .filter(|r| r.dir == Direction::Send && r.data.starts_with(b"Sub\n"))
+      .count();
+    assert_eq!(subs, 2);
+  }
+
+  #[tokio::test]
+  async fn record_and_replay() {
+    let fname = std::env::temp_dir()
+      .join(format!("ddmw-record-{}.cap", std::process::id()));
+
+    // Record a subscription and the reception of a message from the mock
+    // server.
+    let srv = MockServer::tcp().await.unwrap();
+    srv.push_msg(MockMsg {
+      ch: "1".into(),
+      cmd: 5,
+      meta: None,
+      payload: Some(bytes::Bytes::from_static(b"hello\nworld"))
+    });
+    let opts = Options {
+      record: Some(fname.clone()),
+      ..Options::default()
+    };
+    let mut conn = conn::connect_with(srv.protaddr(), None, &opts)
+      .await
+      .unwrap();
+    let si = SubInfo { ch: SubCh::Num(1) };
+    recv::subscribe(&mut conn, si.clone()).await.unwrap();
+    let store = |_: &recv::MsgInfo| Ok((StoreType::None, StoreType::Bytes));
+    recv::recv(&mut conn, store).await.unwrap();
+    let rec = conn.codec_mut().take_recorder().unwrap();
+    rec.finish().await.unwrap();
+    drop(conn);
+    drop(srv);
+
+    // Replay it without the server.
+    let records = load(&fname).unwrap();
+    let _ = std::fs::remove_file(&fname);
+    assert_eq!(records[0].dir, Direction::Send);
+
+    let (client, server) = tokio::io::duplex(4096);
+    let player =
+      tokio::spawn(async move { replay(server, &records, false).await });
+    let mut conn = Framed::new(client, conn::Codec::new());
+    recv::subscribe(&mut conn, si).await.unwrap();
+    let msg = recv::recv(&mut conn, store).await.unwrap();
+    assert_eq!(msg.cmd, 5);
+    match msg.payload {
+      Some(Storage::Bytes(b)) => assert_eq!(&b[..], b"hello\nworld"),
+      _ => panic!("Expected payload")
+    }
+    player.await.unwrap().unwrap();
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,233 @@
+//! Per-connection traffic statistics.
+//!
+//! Each connection established by this crate counts the telegrams, message
+//! metadata bytes and message payload bytes it sends and receives, as well as
+//! the round-trip times of its requests.  The counters live in a [`Stats`]
+//! object which is shared using an `Arc`, so a handle to it can be kept (for
+//! instance by a health endpoint) and read while the connection is in use:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use ddmw_client::conn;
+//!
+//! async fn example() {
+//!   let pa: conn::ProtAddr = "127.0.0.1:8777".parse().unwrap();
+//!   let conn = conn::connect(pa, None).await.unwrap();
+//!   let stats = Arc::clone(conn.codec().stats());
+//!   // ...
+//!   let snap = stats.snapshot();
+//!   println!("{} telegrams sent", snap.tg_sent);
+//! }
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::record::Direction;
+
+
+/// Kind of message content.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Content {
+  Meta,
+  Payload
+}
+
+
+/// Traffic counters of a connection.
+#[derive(Debug)]
+pub struct Stats {
+  tg_sent: AtomicU64,
+  tg_recv: AtomicU64,
+  meta_sent: AtomicU64,
+  meta_recv: AtomicU64,
+  payload_sent: AtomicU64,
+  payload_recv: AtomicU64,
+  requests: AtomicU64,
+  rtt_total: AtomicU64,
+  rtt_min: AtomicU64,
+  rtt_max: AtomicU64
+}
+
+impl Default for Stats {
+  fn default() -> Self {
+    Stats {
+      tg_sent: AtomicU64::new(0),
+      tg_recv: AtomicU64::new(0),
+      meta_sent: AtomicU64::new(0),
+      meta_recv: AtomicU64::new(0),
+      payload_sent: AtomicU64::new(0),
+      payload_recv: AtomicU64::new(0),
+      requests: AtomicU64::new(0),
+      rtt_total: AtomicU64::new(0),
+      rtt_min: AtomicU64::new(u64::MAX),
+      rtt_max: AtomicU64::new(0)
+    }
+  }
+}
+
+impl Stats {
+  pub fn new() -> Self {
+    Stats::default()
+  }
+
+  /// Return a copy of the current counter values.
+  pub fn snapshot(&self) -> Snapshot {
+    let ld = |v: &AtomicU64| v.load(Ordering::Relaxed);
+    let requests = ld(&self.requests);
+    Snapshot {
+      tg_sent: ld(&self.tg_sent),
+      tg_recv: ld(&self.tg_recv),
+      meta_sent: ld(&self.meta_sent),
+      meta_recv: ld(&self.meta_recv),
+      payload_sent: ld(&self.payload_sent),
+      payload_recv: ld(&self.payload_recv),
+      requests,
+      rtt_total: Duration::from_micros(ld(&self.rtt_total)),
+      rtt_min: if requests != 0 {
+        Duration::from_micros(ld(&self.rtt_min))
+      } else {
+        Duration::ZERO
+      },
+      rtt_max: Duration::from_micros(ld(&self.rtt_max))
+    }
+  }
+
+  /// Count a telegram.
+  pub(crate) fn tg(&self, dir: Direction) {
+    let ctr = match dir {
+      Direction::Send => &self.tg_sent,
+      Direction::Recv => &self.tg_recv
+    };
+    ctr.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Count `n` bytes of message content.
+  pub(crate) fn content(&self, dir: Direction, kind: Content, n: u64) {
+    let ctr = match (dir, kind) {
+      (Direction::Send, Content::Meta) => &self.meta_sent,
+      (Direction::Recv, Content::Meta) => &self.meta_recv,
+      (Direction::Send, Content::Payload) => &self.payload_sent,
+      (Direction::Recv, Content::Payload) => &self.payload_recv
+    };
+    ctr.fetch_add(n, Ordering::Relaxed);
+  }
+
+  /// Count a completed request and its round-trip time.
+  pub(crate) fn rtt(&self, dur: Duration) {
+    let us = dur.as_micros().min(u64::MAX as u128) as u64;
+    self.requests.fetch_add(1, Ordering::Relaxed);
+    self.rtt_total.fetch_add(us, Ordering::Relaxed);
+    self.rtt_min.fetch_min(us, Ordering::Relaxed);
+    self.rtt_max.fetch_max(us, Ordering::Relaxed);
+  }
+}
+
+
+/// Point-in-time copy of a connection's [`Stats`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+  /// Number of telegrams sent.
+  pub tg_sent: u64,
+
+  /// Number of telegrams received, including replies and notifications.
+  pub tg_recv: u64,
+
+  /// Number of message metadata bytes sent.
+  pub meta_sent: u64,
+
+  /// Number of message metadata bytes received.
+  pub meta_recv: u64,
+
+  /// Number of message payload bytes sent.
+  pub payload_sent: u64,
+
+  /// Number of message payload bytes received.
+  pub payload_recv: u64,
+
+  /// Number of requests which have received a reply.
+  pub requests: u64,
+
+  /// Sum of the round-trip times of all `requests`.
+  pub rtt_total: Duration,
+
+  /// Shortest round-trip time.
+  pub rtt_min: Duration,
+
+  /// Longest round-trip time.
+  pub rtt_max: Duration
+}
+
+impl Snapshot {
+  /// Return the average round-trip time, or `None` if no request has
+  /// completed yet.
+  pub fn rtt_avg(&self) -> Option<Duration> {
+    if self.requests == 0 {
+      return None;
+    }
+    let avg = self.rtt_total.as_micros() / self.requests as u128;
+    Some(Duration::from_micros(avg as u64))
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::Arc;
+
+  use bytes::Bytes;
+
+  use crate::conn;
+  use crate::msg::{
+    recv::{self, StoreType, SubCh, SubInfo},
+    send::{self, InputType, MsgInfo, Transport}
+  };
+  use crate::testing::{MockMsg, MockServer};
+  use crate::types::AppChannel;
+
+  #[tokio::test]
+  async fn count_traffic() {
+    let srv = MockServer::tcp().await.unwrap();
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    let stats = Arc::clone(conn.codec().stats());
+    assert_eq!(stats.snapshot(), Snapshot::default());
+    assert!(stats.snapshot().rtt_avg().is_none());
+
+    // Msg request plus two content acknowledgements.
+    let xfer = Transport {
+      ch: AppChannel::Num(1)
+    };
+    let mi = MsgInfo {
+      cmd: 1,
+      meta: Some(InputType::VecBuf(b"abc".to_vec())),
+      payload: Some(InputType::Bytes(Bytes::from_static(b"hello")))
+    };
+    send::send(&mut conn, &xfer, &mi).await.unwrap();
+
+    // Sub request, and an incoming message.
+    srv.push_msg(MockMsg {
+      ch: "2".into(),
+      cmd: 2,
+      meta: None,
+      payload: Some(Bytes::from_static(b"world!"))
+    });
+    recv::subscribe(&mut conn, SubInfo { ch: SubCh::Num(2) })
+      .await
+      .unwrap();
+    let store = |_: &recv::MsgInfo| Ok((StoreType::None, StoreType::None));
+    recv::recv(&mut conn, store).await.unwrap();
+
+    let snap = stats.snapshot();
+    assert_eq!(snap.tg_sent, 2);
+    assert_eq!(snap.tg_recv, 5);
+    assert_eq!((snap.meta_sent, snap.payload_sent), (3, 5));
+    assert_eq!((snap.meta_recv, snap.payload_recv), (0, 6));
+    assert_eq!(snap.requests, 2);
+    assert!(snap.rtt_min <= snap.rtt_max);
+    assert!(snap.rtt_avg().unwrap() <= snap.rtt_max);
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,95 @@
+//! Transport stream used by [`Frm`](super::Frm) connections.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+
+/// The underlying transport of a connection.
+pub(crate) enum Io {
+  Tcp(TcpStream),
+
+  #[cfg(unix)]
+  Uds(UnixStream),
+
+  #[cfg(feature = "tls")]
+  Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>)
+}
+
+
+/// A connection's byte stream.
+pub struct Stream {
+  io: Io
+}
+
+impl Stream {
+  pub(crate) fn new(io: Io) -> Self {
+    Stream { io }
+  }
+}
+
+impl AsyncRead for Stream {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>
+  ) -> Poll<io::Result<()>> {
+    match self.get_mut().io {
+      Io::Tcp(ref mut s) => Pin::new(s).poll_read(cx, buf),
+      #[cfg(unix)]
+      Io::Uds(ref mut s) => Pin::new(s).poll_read(cx, buf),
+      #[cfg(feature = "tls")]
+      Io::Tls(ref mut s) => Pin::new(s.as_mut()).poll_read(cx, buf)
+    }
+  }
+}
+
+impl AsyncWrite for Stream {
+  fn poll_write(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8]
+  ) -> Poll<io::Result<usize>> {
+    match self.get_mut().io {
+      Io::Tcp(ref mut s) => Pin::new(s).poll_write(cx, buf),
+      #[cfg(unix)]
+      Io::Uds(ref mut s) => Pin::new(s).poll_write(cx, buf),
+      #[cfg(feature = "tls")]
+      Io::Tls(ref mut s) => Pin::new(s.as_mut()).poll_write(cx, buf)
+    }
+  }
+
+  fn poll_flush(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>
+  ) -> Poll<io::Result<()>> {
+    match self.get_mut().io {
+      Io::Tcp(ref mut s) => Pin::new(s).poll_flush(cx),
+      #[cfg(unix)]
+      Io::Uds(ref mut s) => Pin::new(s).poll_flush(cx),
+      #[cfg(feature = "tls")]
+      Io::Tls(ref mut s) => Pin::new(s.as_mut()).poll_flush(cx)
+    }
+  }
+
+  fn poll_shutdown(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>
+  ) -> Poll<io::Result<()>> {
+    match self.get_mut().io {
+      Io::Tcp(ref mut s) => Pin::new(s).poll_shutdown(cx),
+      #[cfg(unix)]
+      Io::Uds(ref mut s) => Pin::new(s).poll_shutdown(cx),
+      #[cfg(feature = "tls")]
+      Io::Tls(ref mut s) => Pin::new(s.as_mut()).poll_shutdown(cx)
+    }
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,299 @@
+//! TLS transport.
+
+use std::convert::TryFrom;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+
+use tokio_rustls::rustls::{
+  self,
+  pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+  ClientConfig, RootCertStore
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::err::Error;
+
+use super::{host_of, TcpConf, TlsConf};
+
+
+/// Establish a TCP/IP connection to `addr` and perform a TLS handshake over
+/// it.
+pub(crate) async fn connect(
+  addr: &str,
+  conf: &TlsConf,
+  tcp: &TcpConf
+) -> Result<TlsStream<TcpStream>, Error> {
+  let sni = match &conf.sni {
+    Some(sni) => sni.clone(),
+    None => host_of(addr).to_string()
+  };
+  let sni = ServerName::try_from(sni)
+    .map_err(|e| Error::BadParams(format!("TLS server name; {}", e)))?;
+
+  let connector = TlsConnector::from(Arc::new(client_config(conf)?));
+
+  let stream = super::open_tcp(addr, tcp).await?;
+  connector.connect(sni, stream).await.map_err(handshake_err)
+}
+
+
+/// Errors raised by rustls during the handshake, such as a certificate that
+/// fails verification, are reported as [`Error::Tls`], since retrying won't
+/// make them go away.  Other I/O errors may be transient.
+fn handshake_err(err: io::Error) -> Error {
+  match err
+    .get_ref()
+    .and_then(|e| e.downcast_ref::<rustls::Error>())
+  {
+    Some(e) => Error::Tls(e.to_string()),
+    None => err.into()
+  }
+}
+
+
+/// Build a rustls client configuration.
+///
+/// If a CA certificate file has been configured, only server certificates
+/// issued by it are accepted.  Otherwise the platform's trusted root
+/// certificates are used.
+fn client_config(conf: &TlsConf) -> Result<ClientConfig, Error> {
+  let mut roots = RootCertStore::empty();
+  if let Some(ca) = &conf.ca {
+    for cert in load_certs(ca)? {
+      roots.add(cert).map_err(tls_err)?;
+    }
+  } else {
+    let native = rustls_native_certs::load_native_certs();
+    roots.add_parsable_certificates(native.certs);
+  }
+
+  let builder = ClientConfig::builder_with_provider(Arc::new(
+    rustls::crypto::ring::default_provider()
+  ))
+  .with_safe_default_protocol_versions()
+  .map_err(tls_err)?
+  .with_root_certificates(roots);
+
+  match (&conf.cert, &conf.key) {
+    (Some(cert), Some(key)) => {
+      let certs = load_certs(cert)?;
+      let key = PrivateKeyDer::from_pem_file(key).map_err(|e| {
+        Error::BadParams(format!("TLS key {}; {}", key.display(), e))
+      })?;
+      builder.with_client_auth_cert(certs, key).map_err(tls_err)
+    }
+    (None, None) => Ok(builder.with_no_client_auth()),
+    _ => Err(Error::BadParams(
+      "TLS client certificate and key must be specified together".to_string()
+    ))
+  }
+}
+
+
+/// Load all PEM encoded certificates from a file.
+fn load_certs(fname: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+  let certs = CertificateDer::pem_file_iter(fname)
+    .and_then(|it| it.collect::<Result<Vec<_>, _>>())
+    .map_err(|e| {
+      Error::BadParams(format!("TLS certificate {}; {}", fname.display(), e))
+    })?;
+  if certs.is_empty() {
+    return Err(Error::BadParams(format!(
+      "No certificates found in {}",
+      fname.display()
+    )));
+  }
+  Ok(certs)
+}
+
+
+fn tls_err(err: rustls::Error) -> Error {
+  Error::BadParams(format!("TLS; {}", err))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::path::PathBuf;
+
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::TcpListener;
+
+  use tokio_rustls::rustls::{server::WebPkiClientVerifier, ServerConfig};
+  use tokio_rustls::TlsAcceptor;
+
+  use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+  use crate::conn::reconn;
+
+  struct Pki {
+    dir: PathBuf,
+    ca: rcgen::Certificate,
+    ca_key: KeyPair
+  }
+
+  impl Pki {
+    fn new(name: &str) -> Self {
+      let dir = std::env::temp_dir().join(format!(
+        "ddmw-tls-{}-{}",
+        name,
+        std::process::id()
+      ));
+      std::fs::create_dir_all(&dir).unwrap();
+
+      let ca_key = KeyPair::generate().unwrap();
+      let mut params = CertificateParams::new(Vec::new()).unwrap();
+      params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+      let ca = params.self_signed(&ca_key).unwrap();
+      std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+      Pki { dir, ca, ca_key }
+    }
+
+    /// Issue a certificate for `name`, and write it and its key to
+    /// `<name>.pem` and `<name>.key`.
+    fn issue_files(&self, name: &str) -> (PathBuf, PathBuf) {
+      let key = KeyPair::generate().unwrap();
+      let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+      let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+      let certfile = self.dir.join(format!("{}.pem", name));
+      let keyfile = self.dir.join(format!("{}.key", name));
+      std::fs::write(&certfile, cert.pem()).unwrap();
+      std::fs::write(&keyfile, key.serialize_pem()).unwrap();
+      (certfile, keyfile)
+    }
+
+    /// Issue a certificate for `name`, and return it along with its key.
+    fn issue(&self, name: &str) -> (CertificateDer<'static>, KeyPair) {
+      let key = KeyPair::generate().unwrap();
+      let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+      let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+      (cert.der().clone(), key)
+    }
+  }
+
+  impl Drop for Pki {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.dir);
+    }
+  }
+
+  /// Spawn a TLS server which echoes a single line back to the client.  If
+  /// `mtls` is `true` the server requires a client certificate issued by the
+  /// same CA.
+  async fn echo_server(pki: &Pki, name: &str, mtls: bool) -> String {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let (cert, key) = pki.issue(name);
+    let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+      .with_safe_default_protocol_versions()
+      .unwrap();
+    let builder = if mtls {
+      let mut roots = RootCertStore::empty();
+      roots.add(pki.ca.der().clone()).unwrap();
+      let verifier =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+          .build()
+          .unwrap();
+      builder.with_client_cert_verifier(verifier)
+    } else {
+      builder.with_no_client_auth()
+    };
+    let conf = builder.with_single_cert(vec![cert], key).unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(conf));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+      let (sock, _) = listener.accept().await.unwrap();
+      if let Ok(mut tls) = acceptor.accept(sock).await {
+        let mut buf = [0u8; 6];
+        tls.read_exact(&mut buf).await.unwrap();
+        tls.write_all(&buf).await.unwrap();
+      }
+    });
+    addr
+  }
+
+  #[tokio::test]
+  async fn pinned_ca() {
+    let pki = Pki::new("pinned");
+    let addr = echo_server(&pki, "ddmw.test", false).await;
+
+    let conf = TlsConf {
+      ca: Some(pki.dir.join("ca.pem")),
+      sni: Some("ddmw.test".to_string()),
+      ..TlsConf::default()
+    };
+    let mut tls = connect(&addr, &conf, &TcpConf::default()).await.unwrap();
+    tls.write_all(b"Hello\n").await.unwrap();
+    let mut buf = [0u8; 6];
+    tls.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"Hello\n");
+  }
+
+  #[tokio::test]
+  async fn unknown_ca() {
+    let pki = Pki::new("server");
+    let other = Pki::new("other");
+    let addr = echo_server(&pki, "ddmw.test", false).await;
+
+    let conf = TlsConf {
+      ca: Some(other.dir.join("ca.pem")),
+      sni: Some("ddmw.test".to_string()),
+      ..TlsConf::default()
+    };
+    let res = connect(&addr, &conf, &TcpConf::default()).await;
+    let err = res.err().unwrap();
+    assert!(matches!(err, Error::Tls(_)), "{:?}", err);
+    assert!(!reconn::is_transient(&err));
+  }
+
+  #[tokio::test]
+  async fn wrong_name() {
+    let pki = Pki::new("name");
+    let addr = echo_server(&pki, "ddmw.test", false).await;
+
+    let conf = TlsConf {
+      ca: Some(pki.dir.join("ca.pem")),
+      sni: Some("other.test".to_string()),
+      ..TlsConf::default()
+    };
+    let res = connect(&addr, &conf, &TcpConf::default()).await;
+    let err = res.err().unwrap();
+    assert!(matches!(err, Error::Tls(_)), "{:?}", err);
+    assert!(!reconn::is_transient(&err));
+  }
+
+  #[tokio::test]
+  async fn client_cert() {
+    let pki = Pki::new("mtls");
+    let addr = echo_server(&pki, "ddmw.test", true).await;
+    let (cert, key) = pki.issue_files("client");
+
+    let conf = TlsConf {
+      ca: Some(pki.dir.join("ca.pem")),
+      cert: Some(cert),
+      key: Some(key),
+      sni: Some("ddmw.test".to_string())
+    };
+    let mut tls = connect(&addr, &conf, &TcpConf::default()).await.unwrap();
+    tls.write_all(b"Hello\n").await.unwrap();
+    let mut buf = [0u8; 6];
+    tls.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"Hello\n");
+  }
+
+  #[test]
+  fn addr_host() {
+    assert_eq!(host_of("ddmw.local:4100"), "ddmw.local");
+    assert_eq!(host_of("[::1]:4100"), "::1");
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -41,6 +41,18 @@ pub enum Error {
   /// Some expected data is missing.
   MissingData(String),
 
+  /// An operation did not complete within its configured time limit.  The
+  /// `String` describes which operation timed out.
+  Timeout(String),
+
+  /// An earlier reply or write timeout has left the connection in an unknown
+  /// state.  The connection must be dropped.
+  Poisoned,
+
+  /// The TLS handshake failed, for instance because the server's certificate
+  /// could not be verified.
+  Tls(String),
+
   Parse(String),
 
   Figment(String)
@@ -66,7 +78,7 @@ impl std::error::Error for Error {}
 
 impl fmt::Display for Error {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    match &*self {
+    match self {
       Error::Blather(s) => write!(f, "Msg buffer error; {}", s),
       Error::IO(s) => write!(f, "I/O error; {}", s),
       Error::ServerError(p) => write!(f, "Server replied: {}", p),
@@ -78,6 +90,11 @@ impl fmt::Display for Error {
       Error::BadParams(s) => write!(f, "Bad parameters; {}", s),
       Error::InvalidCredentials(s) => write!(f, "Invalid credentials; {}", s),
       Error::MissingData(s) => write!(f, "Missing data; {}", s),
+      Error::Timeout(s) => write!(f, "Timed out; {}", s),
+      Error::Poisoned => {
+        write!(f, "Connection unusable after an earlier timeout")
+      }
+      Error::Tls(s) => write!(f, "TLS handshake failed; {}", s),
       Error::Parse(s) => write!(f, "Parsing failed; {}", s),
       Error::Figment(s) => write!(f, "Figment error; {}", s)
     }
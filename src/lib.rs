@@ -6,12 +6,28 @@
 //! module also provides functions for:
 //! - sending commands and receiving replies.
 //! - ask the server who owns the connection.
+//! - receiving notifications the server sends on its own accord.
+//!
+//! Connections are made over TCP/IP or unix local domain sockets.  If the
+//! `tls` feature is enabled, TLS connections can be made using `tls://`
+//! addresses.  TCP/IP connections can also be tunneled through an HTTP proxy;
+//! see the [`conn::proxy`] module.
 //!
 //! The `connect` method supports optionally authenticating the connection, but
 //! this can also be performed explicitly after the connection has been
 //! established using the [`authenticate()`](auth::Auth::authenticate) method
 //! in the [`auth`] module.
 //!
+//! # Client sessions
+//! The [`Client`] type in the [`client`] module wraps a connection together
+//! with the address and authentication context it was established with, and
+//! exposes the functions of the other modules as methods.  It can be
+//! constructed directly from a [`Config`].
+//!
+//! If the `blocking` feature is enabled, the `blocking` module provides a
+//! synchronous version of `Client` for programs which do not otherwise use
+//! an asynchronous runtime.
+//!
 //! # Application configuration
 //! Most, if not all, DDMW applications will require a few common configuration
 //! parameters.  To this end a common configuration format is specified in the
@@ -36,12 +52,35 @@
 //! # Management
 //! To create management clients the [`mgmt`] module wrapper contains helper
 //! functions for management commands.
+//!
+//! # Logging
+//! Protocol traffic is logged using [`tracing`].  Each request made using
+//! [`sendrecv()`] gets a `request` span, and the telegrams it exchanges are
+//! logged at the debug level along with the sizes of message metadata and
+//! payloads.  The values of credential parameters, such as `Pass` and `Tkn`,
+//! are always redacted.
+//!
+//! For reproducing problems, the complete traffic of a connection can be
+//! recorded to a capture file, and later replayed, using the
+//! [`conn::record`] module.
+//!
+//! Each connection also counts the telegrams and message content it sends
+//! and receives, and the round-trip times of its requests.  See the
+//! [`conn::stats`] module.
+//!
+//! # Testing
+//! If the `testing` feature is enabled, the `testing` module provides an
+//! in-process mock DDMW core server which integrations can run their tests
+//! against.
 
 //#![deny(missing_docs)]
 //#![deny(missing_crate_level_docs)]
 //#![deny(missing_doc_code_examples)]
 
 pub mod auth;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod client;
 pub mod conf;
 pub mod conn;
 pub mod err;
@@ -49,12 +88,17 @@ pub mod mgmt;
 pub mod msg;
 pub mod probe;
 pub mod strm;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod types;
 
+mod trace;
 mod utils;
 
 pub use err::Error;
 
+pub use client::Client;
+
 pub use conn::{expect_okfail, sendrecv};
 
 pub use conf::Config;
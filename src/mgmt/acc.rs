@@ -4,7 +4,7 @@ use tokio::io::{AsyncRead, AsyncWrite};
 
 use tokio_util::codec::Framed;
 
-use crate::conn::sendrecv;
+use crate::conn::{pipeline::Pipeline, sendrecv, Codec};
 use crate::types::ObjRef;
 
 use crate::err::Error;
@@ -22,10 +22,42 @@ pub struct Account {
 /// Get information about an account.
 ///
 /// If `acc` is `None` the current connection's owner will be returned.
+#[tracing::instrument(level = "debug", skip_all)]
 pub async fn rd<T: AsyncRead + AsyncWrite + Unpin>(
-  conn: &mut Framed<T, blather::Codec>,
+  conn: &mut Framed<T, Codec>,
   acc: Option<ObjRef>
 ) -> Result<Account, Error> {
+  let tg = rd_tg(acc)?;
+  let params = sendrecv(conn, &tg).await?;
+  parse_account(&params)
+}
+
+
+/// Get information about several accounts.
+///
+/// The requests are pipelined, so several of them are in flight at once.
+/// The results are returned in the same order as `accs`; if one of the
+/// accounts could not be read, its entry holds the error.
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn rd_many<T: AsyncRead + AsyncWrite + Unpin>(
+  conn: &mut Framed<T, Codec>,
+  accs: Vec<ObjRef>
+) -> Result<Vec<Result<Account, Error>>, Error> {
+  let mut pl = Pipeline::new();
+  for acc in accs {
+    pl.push(rd_tg(Some(acc))?);
+  }
+  let res = pl.run(conn).await?;
+  Ok(
+    res
+      .into_iter()
+      .map(|r| r.and_then(|params| parse_account(&params)))
+      .collect()
+  )
+}
+
+
+fn rd_tg(acc: Option<ObjRef>) -> Result<blather::Telegram, Error> {
   let mut tg = blather::Telegram::new_topic("RdAcc")?;
 
   if let Some(acc) = acc {
@@ -39,8 +71,11 @@ pub async fn rd<T: AsyncRead + AsyncWrite + Unpin>(
     }
   }
 
-  let params = sendrecv(conn, &tg).await?;
+  Ok(tg)
+}
+
 
+fn parse_account(params: &blather::Params) -> Result<Account, Error> {
   let id = params.get_int::<i64>("Id")?;
   let name = params.get_param::<String>("Name")?;
   let lock = params.get_bool("Lock")?;
@@ -69,18 +104,16 @@ pub struct LsEntry {
 /// This will only retreive a list of numeric account identifiers and the
 /// associated unique account name.  To get detailed information about each
 /// account the application needs to call [`rd`](self::rd) for each
-/// entry.
+/// entry, or [`rd_many`] for all of them at once.
+#[tracing::instrument(level = "debug", skip_all)]
 pub async fn ls<T: AsyncRead + AsyncWrite + Unpin>(
-  conn: &mut Framed<T, blather::Codec>,
+  conn: &mut Framed<T, Codec>,
   inclock: bool
 ) -> Result<Vec<LsEntry>, Error> {
   let mut tg = blather::Telegram::new_topic("LsAcc")?;
 
-  match inclock {
-    true => {
-      tg.add_bool("All", true)?;
-    }
-    _ => {}
+  if inclock {
+    tg.add_bool("All", true)?;
   }
 
   let params = sendrecv(conn, &tg).await?;
@@ -140,8 +173,9 @@ pub struct WrAccount {
 
 
 /// Update an account.
+#[tracing::instrument(level = "debug", skip_all)]
 pub async fn wr<T: AsyncRead + AsyncWrite + Unpin>(
-  conn: &mut Framed<T, blather::Codec>,
+  conn: &mut Framed<T, Codec>,
   acc: ObjRef,
   ai: WrAccount
 ) -> Result<(), Error> {
@@ -191,8 +225,9 @@ pub async fn wr<T: AsyncRead + AsyncWrite + Unpin>(
 
 
 /// Remove an account.
+#[tracing::instrument(level = "debug", skip_all)]
 pub async fn rm<T: AsyncRead + AsyncWrite + Unpin>(
-  conn: &mut Framed<T, blather::Codec>,
+  conn: &mut Framed<T, Codec>,
   acc: ObjRef
 ) -> Result<(), Error> {
   let mut tg = blather::Telegram::new_topic("RmAcc")?;
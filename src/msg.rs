@@ -2,6 +2,7 @@
 
 pub mod recv;
 pub mod send;
+pub mod sup;
 
 pub use recv::{recv, recvloop, recvloop_a};
 pub use send::send;
@@ -5,30 +5,43 @@ use std::path::PathBuf;
 
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use tokio_stream::StreamExt;
-
 use tokio_util::codec::Framed;
 
 use bytes::{Bytes, BytesMut};
 
 use blather::{codec, KVLines, Params, Telegram};
 
+use crate::conn::{self, record::Direction, stats::Content, Codec};
 use crate::err::Error;
+use crate::trace;
+use crate::types::AppChannel;
 
 
+#[derive(Clone, Debug)]
 pub enum SubCh {
   Num(u8),
   Name(String)
 }
 
+impl From<AppChannel> for SubCh {
+  fn from(ch: AppChannel) -> Self {
+    match ch {
+      AppChannel::Num(ch) => SubCh::Num(ch),
+      AppChannel::Name(nm) => SubCh::Name(nm)
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
 pub struct SubInfo {
   pub ch: SubCh
 }
 
 
 /// Subscribe to an application message channel.
+#[tracing::instrument(level = "debug", skip_all, fields(ch = ?subinfo.ch))]
 pub async fn subscribe<C>(
-  conn: &mut Framed<C, blather::Codec>,
+  conn: &mut Framed<C, Codec>,
   subinfo: SubInfo
 ) -> Result<(), Error>
 where
@@ -144,6 +157,16 @@ pub struct MsgInfo {
 ///     the DDMW server, then the library will always return a
 ///     [`Storage::LocalFile`] for this content.
 ///
+/// # Cancel safety
+/// This function is not cancel safe; if it is cancelled while a message's
+/// metadata or payload is being received, the connection is left in an
+/// undefined state.  Use a [`Receiver`] where cancellation can happen, for
+/// instance in a `tokio::select!`.
+///
+/// For the same reason, if the idle timeout expires while a message's
+/// metadata or payload is being received, the connection is poisoned.  A
+/// `Receiver` can instead resume the message once more data arrives.
+///
 /// # Example
 ///
 /// ```no_run
@@ -160,7 +183,7 @@ pub struct MsgInfo {
 ///
 /// // Enter an loop which keeps receiving messages until the connection is
 /// // dropped.
-/// async fn get_message(conn: &mut Framed<TcpStream, blather::Codec>) -> Msg {
+/// async fn get_message(conn: &mut Framed<TcpStream, conn::Codec>) -> Msg {
 ///   msg::recv(
 ///     conn,
 ///     |_mi| {
@@ -176,41 +199,27 @@ pub struct MsgInfo {
 /// }
 /// ```
 pub async fn recv<C, S>(
-  conn: &mut Framed<C, blather::Codec>,
+  conn: &mut Framed<C, Codec>,
   storeq: S
 ) -> Result<Msg, Error>
 where
   C: AsyncRead + AsyncWrite + Unpin,
   S: FnMut(&MsgInfo) -> Result<(StoreType, StoreType), Error>
 {
-  // Wait for the next frame, and exiect it to be a Telegram.
-  if let Some(o) = conn.next().await {
-    let o = o?;
-    match o {
-      codec::Input::Telegram(tg) => {
-        // Got the expetected Telegram -- make sure that it's has a "Msg"
-        // topic.
-        if let Some(topic) = tg.get_topic() {
-          if topic == "Msg" {
-            // Convert to a Params buffer, since we no longer need the topic
-            let mp = tg.into_params();
-
-            return proc_inbound_msg(conn, mp, storeq).await;
-          } else if topic == "Fail" {
-            return Err(Error::ServerError(tg.into_params()));
-          }
-        }
-      }
-      _ => {
-        return Err(Error::BadState(
-          "Unexpected codec input type.".to_string()
-        ));
-      }
-    }
-    return Err(Error::bad_state("Unexpected reply from server."));
-  }
+  let mut rx = Receiver::new();
+  let res = rx.recv(conn, storeq).await;
+  forget(rx, conn);
+  res
+}
+
 
-  Err(Error::Disconnected)
+/// Drop a receiver.  If it has a message in progress, the rest of the message
+/// can no longer be received, so the connection is poisoned.
+pub(crate) fn forget<C>(rx: Receiver, conn: &mut Framed<C, Codec>) {
+  if rx.in_progress() {
+    tracing::warn!("receiver dropped with a message in progress");
+    conn.codec_mut().poison();
+  }
 }
 
 
@@ -219,6 +228,10 @@ where
 ///
 /// Returns `Ok()` if the loop was terminated by the killswitch.
 ///
+/// If the killswitch is triggered while a message is being received, the
+/// message is received in full, and processed, before the loop terminates.
+/// This leaves the connection ready to receive the next message.
+///
 /// # Example
 /// The following example illustrates how to write a function that will keep
 /// receiving messages.
@@ -234,7 +247,7 @@ where
 ///
 /// // Enter an loop which keeps receiving messages until the connection is
 /// // dropped.
-/// async fn get_messages(conn: &mut Framed<TcpStream, blather::Codec>) {
+/// async fn get_messages(conn: &mut Framed<TcpStream, conn::Codec>) {
 ///   let mut idx = 0;
 ///
 ///   msg::recvloop(
@@ -278,7 +291,26 @@ where
 /// ```
 // ToDo: yield, when it becomes available
 pub async fn recvloop<C, S, P>(
-  conn: &mut Framed<C, blather::Codec>,
+  conn: &mut Framed<C, Codec>,
+  kill: Option<killswitch::Shutdown>,
+  storeq: S,
+  procmsg: P
+) -> Result<(), Error>
+where
+  C: AsyncRead + AsyncWrite + Unpin,
+  S: FnMut(&MsgInfo) -> Result<(StoreType, StoreType), Error>,
+  P: Fn(Msg) -> Result<(), Error>
+{
+  let mut rx = Receiver::new();
+  let res = recvloop_with(&mut rx, conn, kill, storeq, procmsg).await;
+  forget(rx, conn);
+  res
+}
+
+/// Same as [`recvloop()`], but receives messages using `rx`.
+pub(crate) async fn recvloop_with<C, S, P>(
+  rx: &mut Receiver,
+  conn: &mut Framed<C, Codec>,
   kill: Option<killswitch::Shutdown>,
   mut storeq: S,
   procmsg: P
@@ -291,12 +323,17 @@ where
   if let Some(kill) = kill {
     loop {
       tokio::select! {
-        msg = recv(conn, &mut storeq) => {
+        msg = rx.recv(conn, &mut storeq) => {
           let msg = msg?;
           procmsg(msg)?;
         }
         _ = kill.wait() => {
-          // An external termination request was received, so break out of loop
+          // An external termination request was received.  Finish receiving
+          // the current message, if any, then break out of loop.
+          if rx.in_progress() {
+            let msg = rx.recv(conn, &mut storeq).await?;
+            procmsg(msg)?;
+          }
           break;
         }
       }
@@ -304,7 +341,7 @@ where
   } else {
     // No killswitch supplied -- just keep running until disconnection
     loop {
-      let msg = recv(conn, &mut storeq).await?;
+      let msg = rx.recv(conn, &mut storeq).await?;
       procmsg(msg)?;
     }
   }
@@ -329,7 +366,7 @@ where
 ///
 /// // Enter an loop which keeps receiving messages until the connection is
 /// // dropped.
-/// async fn get_messages(conn: &mut Framed<TcpStream, blather::Codec>) {
+/// async fn get_messages(conn: &mut Framed<TcpStream, conn::Codec>) {
 ///   let mut idx = 0;
 ///
 ///   msg::recvloop_a(
@@ -348,7 +385,27 @@ where
 /// }
 /// ```
 pub async fn recvloop_a<C, S, F, P>(
-  conn: &mut Framed<C, blather::Codec>,
+  conn: &mut Framed<C, Codec>,
+  kill: Option<killswitch::Shutdown>,
+  storeq: S,
+  procmsg: P
+) -> Result<(), Error>
+where
+  C: AsyncRead + AsyncWrite + Unpin,
+  S: FnMut(&MsgInfo) -> Result<(StoreType, StoreType), Error>,
+  F: Future<Output = Result<(), Error>>,
+  P: Fn(Msg) -> F
+{
+  let mut rx = Receiver::new();
+  let res = recvloop_a_with(&mut rx, conn, kill, storeq, procmsg).await;
+  forget(rx, conn);
+  res
+}
+
+/// Same as [`recvloop_a()`], but receives messages using `rx`.
+pub(crate) async fn recvloop_a_with<C, S, F, P>(
+  rx: &mut Receiver,
+  conn: &mut Framed<C, Codec>,
   kill: Option<killswitch::Shutdown>,
   mut storeq: S,
   procmsg: P
@@ -362,12 +419,17 @@ where
   if let Some(kill) = kill {
     loop {
       tokio::select! {
-        msg = recv(conn, &mut storeq) => {
+        msg = rx.recv(conn, &mut storeq) => {
           let msg = msg?;
           procmsg(msg).await?;
         }
         _ = kill.wait() => {
-          // An external termination request was received, so break out of loop
+          // An external termination request was received.  Finish receiving
+          // the current message, if any, then break out of loop.
+          if rx.in_progress() {
+            let msg = rx.recv(conn, &mut storeq).await?;
+            procmsg(msg).await?;
+          }
           break;
         }
       }
@@ -375,7 +437,7 @@ where
   } else {
     // No killswitch supplied -- just keep running until disconnection
     loop {
-      let msg = recv(conn, &mut storeq).await?;
+      let msg = rx.recv(conn, &mut storeq).await?;
       procmsg(msg).await?;
     }
   }
@@ -384,143 +446,358 @@ where
 }
 
 
-async fn proc_inbound_msg<C, S>(
-  conn: &mut Framed<C, blather::Codec>,
-  mp: Params,
-  mut storeq: S
-) -> Result<Msg, Error>
-where
-  C: AsyncRead + AsyncWrite + Unpin,
-  S: FnMut(&MsgInfo) -> Result<(StoreType, StoreType), Error>
-{
-  let metalen = if mp.have("MetaLen") {
-    mp.get_param::<u32>("MetaLen")?
-  } else {
-    0u32
-  };
+/// A message which has been announced by the server, but whose metadata
+/// and/or payload has not been received in full yet.
+struct Partial {
+  cmd: u32,
+  metalen: u32,
+  payloadlen: u64,
 
-  let payloadlen = if mp.have("Len") {
-    mp.get_param::<u64>("Len")?
-  } else {
-    0u64
-  };
+  /// Which content the codec has been set up to receive next.
+  next: Content,
+
+  /// Received metadata.
+  meta: Option<Storage>,
+
+  /// Requested payload storage, until the codec has been set up to receive
+  /// the payload.
+  payload_store: Option<StoreType>,
+
+  /// The message has been aborted; its remaining content is received but
+  /// thrown away.
+  discard: bool
+}
+
+
+/// Cancel-safe message receiver.
+///
+/// A message arrives as a `Msg` telegram, optionally followed by metadata and
+/// payload.  A `Receiver` keeps track of how far into a message it has come,
+/// so if a call to [`recv()`](Self::recv) is cancelled (for instance because
+/// it lost a race in a `tokio::select!`) while content is being received, the
+/// next call to `recv()` picks up where the cancelled one left off.
+///
+/// If the partially received message is no longer wanted it can be
+/// discarded using [`abort()`](Self::abort), which leaves the connection
+/// ready to receive the next message.
+///
+/// A `Receiver` must only be used with a single connection, and other
+/// requests must not be made on the connection while a message is in
+/// progress.
+#[derive(Default)]
+pub struct Receiver {
+  partial: Option<Partial>
+}
+
+impl Receiver {
+  /// Create a receiver which has no message in progress.
+  pub fn new() -> Self {
+    Receiver::default()
+  }
+
+  /// Returns `true` if a message has been partially received.
+  pub fn in_progress(&self) -> bool {
+    self.partial.is_some()
+  }
+
+  /// Receive a single message.
+  ///
+  /// See the [`recv()`] function for details about `storeq`.  If a message
+  /// is already in progress `storeq` is not called again; the message is
+  /// completed using the storage types that were originally requested.
+  ///
+  /// # Cancel safety
+  /// This method is cancel safe.
+  #[tracing::instrument(level = "debug", skip_all)]
+  pub async fn recv<C, S>(
+    &mut self,
+    conn: &mut Framed<C, Codec>,
+    mut storeq: S
+  ) -> Result<Msg, Error>
+  where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: FnMut(&MsgInfo) -> Result<(StoreType, StoreType), Error>
+  {
+    loop {
+      if self.partial.is_some() {
+        if let Some(msg) = self.step(conn).await? {
+          return Ok(msg);
+        }
+        continue;
+      }
+
+      // Wait for the next frame, and exiect it to be a Telegram.
+      let tg = match conn::next_frame(conn, false).await? {
+        Some(codec::Input::Telegram(tg)) => tg,
+        Some(_) => {
+          return Err(Error::BadState(
+            "Unexpected codec input type.".to_string()
+          ));
+        }
+        None => return Err(Error::Disconnected)
+      };
+
+      // Got the expetected Telegram -- make sure that it's has a "Msg" topic.
+      tracing::debug!(tg = %trace::tg(&tg), "recv");
+      match tg.get_topic() {
+        Some("Msg") => {
+          // Convert to a Params buffer, since we no longer need the topic
+          let mp = tg.into_params();
+
+          if let Some(msg) = self.begin(conn, mp, &mut storeq)? {
+            return Ok(msg);
+          }
+          continue;
+        }
+        Some("Fail") => return Err(Error::ServerError(tg.into_params())),
+        _ => {}
+      }
+
+      // Anything else is a notification, which is only acceptable if the
+      // application has registered a handler for them.
+      if conn::dispatch_notification(conn, tg).is_err() {
+        return Err(Error::bad_state("Unexpected reply from server."));
+      }
+    }
+  }
+
+  /// Discard the message in progress, if any.
+  ///
+  /// The remaining content of the message is read from the connection and
+  /// thrown away, and content which has already been stored in files is
+  /// removed.  On success the connection is ready to receive the next
+  /// message.
+  ///
+  /// # Cancel safety
+  /// This method is cancel safe; if it is cancelled the message remains
+  /// aborted, and its remainder is discarded by the next call to `abort()` or
+  /// [`recv()`](Self::recv).
+  pub async fn abort<C>(
+    &mut self,
+    conn: &mut Framed<C, Codec>
+  ) -> Result<(), Error>
+  where
+    C: AsyncRead + AsyncWrite + Unpin
+  {
+    if let Some(p) = &mut self.partial {
+      tracing::debug!(cmd = p.cmd, "aborting message");
+      p.discard = true;
+      if p.payload_store.is_some() {
+        p.payload_store = Some(StoreType::None);
+      }
+    }
+    while self.partial.is_some() {
+      self.step(conn).await?;
+    }
+    Ok(())
+  }
+
+  /// Process a `Msg` telegram, and set up the codec to receive its first
+  /// content.  Returns the message if it has no content.
+  fn begin<C, S>(
+    &mut self,
+    conn: &mut Framed<C, Codec>,
+    mp: Params,
+    storeq: &mut S
+  ) -> Result<Option<Msg>, Error>
+  where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: FnMut(&MsgInfo) -> Result<(StoreType, StoreType), Error>
+  {
+    let metalen = if mp.have("MetaLen") {
+      mp.get_param::<u32>("MetaLen")?
+    } else {
+      0u32
+    };
+
+    let payloadlen = if mp.have("Len") {
+      mp.get_param::<u64>("Len")?
+    } else {
+      0u64
+    };
+
+    // Parse command
+    let cmd = if mp.have("Cmd") {
+      mp.get_param::<u32>("Cmd")?
+    } else {
+      0
+    };
+
+    // ToDo: Parse mp and check if metadata and/or payload is passed from the
+    //       server using a local file path.  If it is, then return it to the
+    //       application using Storage::LocalFile(PathBuf).
+
+    // If the Params contains either a Len or a MetaLen keyword, then
+    // call the application callback to determine how it wants the data
+    // stored.
+    // ToDo: - If metadata and payload are stored as "local files", then
+    //         don't call application; force to Storage::LocalFile
+    if metalen == 0 && payloadlen == 0 {
+      return Ok(Some(Msg {
+        cmd,
+        meta: None,
+        payload: None
+      }));
+    }
 
-  // Parse command
-  let cmd = if mp.have("Cmd") {
-    mp.get_param::<u32>("Cmd")?
-  } else {
-    0
-  };
-
-  // ToDo: Parse mp and check if metadata and/or payload is passed from the
-  //       server using a local file path.  If it is, then return it to the
-  //       application using Storage::LocalFile(PathBuf).
-
-  // If the Params contains either a Len or a MetaLen keyword, then
-  // call the application callback to determine how it wants the data
-  // stored.
-  // ToDo: - If metadata and payload are stored as "local files", then don't
-  //         call application; force to Storage::LocalFile
-  let (meta_store, payload_store) = if metalen != 0 || payloadlen != 0 {
     // Call the application callback, passing a few Msg parameters, to ask it
     // in what form it would like the metadata and payload.
-
     let mi = MsgInfo {
       cmd,
       metalen,
       payloadlen
     };
 
+    tracing::debug!(cmd, metalen, payloadlen, "incoming message");
+
     let (ms, ps) = storeq(&mi)?;
-    let ms = if metalen != 0 { Some(ms) } else { None };
-    let ps = if metalen != 0 { Some(ps) } else { None };
-    (ms, ps)
-  } else {
-    (None, None)
-  };
-
-  //
-  // At this point, if meta_store is None, it means there was no message
-  // metadata, and we'll skip this altogether and return None to the
-  // application for the metadata.
-  //
-  // If meta_store is Some, then request the appropriate type from the
-  // blather's Codec.
-  //
-  let meta = if let Some(meta_store) = meta_store {
-    match meta_store {
-      StoreType::None => {
-        // This happens if the MetaLen is non-zero, but the callback says it
-        // doesn't want the data.
-        conn.codec_mut().skip(metalen as usize)?;
-      }
-      StoreType::Bytes => {
-        conn.codec_mut().expect_bytes(metalen as usize)?;
-      }
-      StoreType::BytesMut => {
-        conn.codec_mut().expect_bytesmut(metalen as usize)?;
-      }
-      StoreType::Params => {
-        conn.codec_mut().expect_params();
-      }
-      StoreType::KVLines => {
-        conn.codec_mut().expect_kvlines();
-      }
-      StoreType::File(ref fname) => {
-        conn.codec_mut().expect_file(fname, metalen as usize)?;
+    let mut payload_store = if payloadlen != 0 { Some(ps) } else { None };
+
+    // The payload can only be requested from the codec once the metadata, if
+    // any, has been received.
+    let next = if metalen != 0 {
+      expect_content(conn, &ms, metalen.into())?;
+      Content::Meta
+    } else {
+      if let Some(ps) = payload_store.take() {
+        expect_content(conn, &ps, payloadlen)?;
       }
-    }
+      Content::Payload
+    };
 
-    get_content(conn).await?
-  } else {
-    None
-  };
+    self.partial = Some(Partial {
+      cmd,
+      metalen,
+      payloadlen,
+      next,
+      meta: None,
+      payload_store,
+      discard: false
+    });
+    Ok(None)
+  }
 
+  /// Receive the next content of the message in progress.  Returns the
+  /// message once it has been received in full.
+  ///
+  /// The receiver's state is only updated after the content has arrived, so
+  /// if this is cancelled it can simply be called again.
+  async fn step<C>(
+    &mut self,
+    conn: &mut Framed<C, Codec>
+  ) -> Result<Option<Msg>, Error>
+  where
+    C: AsyncRead + AsyncWrite + Unpin
+  {
+    let (next, len) = match &self.partial {
+      Some(p) => match p.next {
+        Content::Meta => (Content::Meta, u64::from(p.metalen)),
+        Content::Payload => (Content::Payload, p.payloadlen)
+      },
+      None => return Ok(None)
+    };
 
-  let payload = if let Some(payload_store) = payload_store {
-    match payload_store {
-      StoreType::None => {
-        // This happens if the Len is non-zero, but the callback says it
-        // doesn't want the data.
-        conn.codec_mut().skip(payloadlen as usize)?;
-      }
-      StoreType::Bytes => {
-        conn.codec_mut().expect_bytes(payloadlen as usize)?;
-      }
-      StoreType::BytesMut => {
-        conn.codec_mut().expect_bytesmut(payloadlen as usize)?;
-      }
-      StoreType::Params => {
-        conn.codec_mut().expect_params();
-      }
-      StoreType::KVLines => {
-        conn.codec_mut().expect_kvlines();
+    let content = match get_content(conn, next, len).await {
+      Ok(content) => content,
+      Err(e) => {
+        // An idle timeout leaves the connection intact, so the message is
+        // resumed by the next call.  After any other error the connection is
+        // in an unknown state, so there's no point in trying to resume it.
+        if !matches!(e, Error::Timeout(_)) {
+          self.partial = None;
+        }
+        return Err(e);
       }
-      StoreType::File(ref fname) => {
-        conn.codec_mut().expect_file(fname, payloadlen as usize)?;
+    };
+
+    let p = match &mut self.partial {
+      Some(p) => p,
+      None => return Ok(None)
+    };
+    if let Content::Meta = next {
+      tracing::debug!(len, "metadata received");
+      p.meta = content;
+      if let Some(ps) = p.payload_store.take() {
+        p.next = Content::Payload;
+        if let Err(e) = expect_content(conn, &ps, p.payloadlen) {
+          self.partial = None;
+          return Err(e);
+        }
+        return Ok(None);
       }
+      return Ok(self.finish(None));
     }
+    tracing::debug!(len, "payload received");
+    Ok(self.finish(content))
+  }
 
-    get_content(conn).await?
-  } else {
-    None
-  };
+  /// Complete the message in progress.  Returns `None` if the message was
+  /// aborted.
+  fn finish(&mut self, payload: Option<Storage>) -> Option<Msg> {
+    let p = self.partial.take()?;
+    if p.discard {
+      for st in [p.meta, payload].iter().flatten() {
+        if let Storage::File(fname) = st {
+          let _ = std::fs::remove_file(fname);
+        }
+      }
+      return None;
+    }
+    Some(Msg {
+      cmd: p.cmd,
+      meta: p.meta,
+      payload
+    })
+  }
+}
 
 
-  Ok(Msg { cmd, meta, payload })
+/// Set up the codec to receive `len` bytes of content in the form requested
+/// by `store`.
+fn expect_content<C>(
+  conn: &mut Framed<C, Codec>,
+  store: &StoreType,
+  len: u64
+) -> Result<(), Error> {
+  let codec = conn.codec_mut();
+  match store {
+    StoreType::None => {
+      // This happens if the length is non-zero, but the callback says it
+      // doesn't want the data.
+      codec.skip(len as usize)?;
+    }
+    StoreType::Bytes => {
+      codec.expect_bytes(len as usize)?;
+    }
+    StoreType::BytesMut => {
+      codec.expect_bytesmut(len as usize)?;
+    }
+    StoreType::Params => {
+      codec.expect_params();
+    }
+    StoreType::KVLines => {
+      codec.expect_kvlines();
+    }
+    StoreType::File(ref fname) => {
+      codec.expect_file(fname, len as usize)?;
+    }
+  }
+  Ok(())
 }
 
 
-/// Translate an incoming frame from the [`blather::Codec`] into a [`Storage`]
-/// type.
+/// Translate an incoming frame from the [`Codec`] into a [`Storage`]
+/// type, and count its `len` bytes in the connection's statistics.
 async fn get_content<C>(
-  conn: &mut Framed<C, blather::Codec>
+  conn: &mut Framed<C, Codec>,
+  kind: Content,
+  len: u64
 ) -> Result<Option<Storage>, Error>
 where
   C: AsyncRead + AsyncWrite + Unpin
 {
-  if let Some(o) = conn.next().await {
-    let o = o?;
+  if let Some(o) = conn::next_frame(conn, true).await? {
+    conn.codec().stats().content(Direction::Recv, kind, len);
     match o {
       codec::Input::SkipDone => Ok(None),
       codec::Input::Bytes(bytes) => Ok(Some(Storage::Bytes(bytes))),
@@ -535,4 +812,243 @@ where
   }
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use tokio::io::AsyncWriteExt;
+
+  use crate::testing::{Fault, MockMsg, MockServer};
+
+  fn content(st: Option<Storage>) -> Option<Bytes> {
+    match st {
+      Some(Storage::Bytes(b)) => Some(b),
+      None => None,
+      _ => panic!("Expected Bytes storage")
+    }
+  }
+
+  #[tokio::test]
+  async fn recv_content() {
+    let srv = MockServer::tcp().await.unwrap();
+    srv.push_msg(MockMsg {
+      ch: "7".into(),
+      cmd: 1,
+      meta: Some(Bytes::from_static(b"meta")),
+      payload: Some(Bytes::from_static(b"payload"))
+    });
+    srv.push_msg(MockMsg {
+      ch: "7".into(),
+      cmd: 3,
+      meta: None,
+      payload: None
+    });
+
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    subscribe(&mut conn, SubInfo { ch: SubCh::Num(7) })
+      .await
+      .unwrap();
+
+    let store = |_: &MsgInfo| Ok((StoreType::Bytes, StoreType::Bytes));
+
+    let msg = recv(&mut conn, store).await.unwrap();
+    assert_eq!(msg.cmd, 1);
+    assert_eq!(content(msg.meta).as_deref(), Some(&b"meta"[..]));
+    assert_eq!(content(msg.payload).as_deref(), Some(&b"payload"[..]));
+
+    let msg = recv(&mut conn, store).await.unwrap();
+    assert_eq!(msg.cmd, 3);
+    assert!(msg.meta.is_none() && msg.payload.is_none());
+  }
+
+  #[tokio::test]
+  async fn payload_without_meta() {
+    let srv = MockServer::tcp().await.unwrap();
+    srv.push_msg(MockMsg {
+      ch: "7".into(),
+      cmd: 2,
+      meta: None,
+      payload: Some(Bytes::from_static(b"only payload"))
+    });
+
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    subscribe(&mut conn, SubInfo { ch: SubCh::Num(7) })
+      .await
+      .unwrap();
+
+    let store = |_: &MsgInfo| Ok((StoreType::Bytes, StoreType::Bytes));
+    let msg = recv(&mut conn, store).await.unwrap();
+    assert_eq!(msg.cmd, 2);
+    assert!(msg.meta.is_none());
+    assert_eq!(content(msg.payload).as_deref(), Some(&b"only payload"[..]));
+  }
+
+  #[tokio::test]
+  async fn recv_notification() {
+    let srv = MockServer::tcp().await.unwrap();
+    srv.push_notification(Telegram::new_topic("Logout").unwrap());
+    srv.push_msg(MockMsg {
+      ch: "1".into(),
+      cmd: 4,
+      ..MockMsg::default()
+    });
+
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    let mut rx = conn::notifications(&mut conn);
+    subscribe(&mut conn, SubInfo { ch: SubCh::Num(1) })
+      .await
+      .unwrap();
+    let store = |_: &MsgInfo| Ok((StoreType::None, StoreType::None));
+    let msg = recv(&mut conn, store).await.unwrap();
+    assert_eq!(msg.cmd, 4);
+    assert_eq!(rx.try_recv().unwrap().get_topic(), Some("Logout"));
+  }
+
+  #[tokio::test]
+  async fn recvloop_kill() {
+    let srv = MockServer::tcp().await.unwrap();
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    subscribe(
+      &mut conn,
+      SubInfo {
+        ch: SubCh::Name("hello".into())
+      }
+    )
+    .await
+    .unwrap();
+
+    let (ks, kill) = killswitch::killswitch();
+    srv.push_msg(MockMsg {
+      ch: "hello".into(),
+      cmd: 9,
+      ..MockMsg::default()
+    });
+    recvloop(
+      &mut conn,
+      Some(kill),
+      |_| Ok((StoreType::None, StoreType::None)),
+      |msg| {
+        assert_eq!(msg.cmd, 9);
+        ks.trigger();
+        Ok(())
+      }
+    )
+    .await
+    .unwrap();
+  }
+
+  #[tokio::test]
+  async fn resume_and_abort() {
+    let (client, mut server) = tokio::io::duplex(4096);
+    let mut conn = Framed::new(client, Codec::new());
+    let mut rx = Receiver::new();
+    let store = |_: &MsgInfo| Ok((StoreType::Bytes, StoreType::Bytes));
+
+    // Cancel the reception half-way through the payload, then resume it.
+    server
+      .write_all(b"Msg\nCmd 1\nLen 10\n\nhello")
+      .await
+      .unwrap();
+    let res = tokio::time::timeout(
+      std::time::Duration::from_millis(50),
+      rx.recv(&mut conn, store)
+    )
+    .await;
+    assert!(res.is_err() && rx.in_progress());
+    server.write_all(b"world").await.unwrap();
+    let msg = rx.recv(&mut conn, store).await.unwrap();
+    assert_eq!(content(msg.payload).as_deref(), Some(&b"helloworld"[..]));
+    assert!(!rx.in_progress());
+
+    // Abort a message half-way through its metadata; the rest of it is
+    // thrown away, and the next message is received intact.
+    server
+      .write_all(b"Msg\nCmd 2\nMetaLen 4\nLen 3\n\nme")
+      .await
+      .unwrap();
+    let res = tokio::time::timeout(
+      std::time::Duration::from_millis(50),
+      rx.recv(&mut conn, store)
+    )
+    .await;
+    assert!(res.is_err() && rx.in_progress());
+    server
+      .write_all(b"taabcMsg\nCmd 3\nLen 2\n\nok")
+      .await
+      .unwrap();
+    rx.abort(&mut conn).await.unwrap();
+    assert!(!rx.in_progress());
+    let msg = rx.recv(&mut conn, store).await.unwrap();
+    assert_eq!(msg.cmd, 3);
+    assert_eq!(content(msg.payload).as_deref(), Some(&b"ok"[..]));
+  }
+
+  #[tokio::test]
+  async fn idle_timeout() {
+    let (client, mut server) = tokio::io::duplex(4096);
+    let mut codec = Codec::new();
+    codec.set_timeouts(conn::Timeouts {
+      idle: Some(std::time::Duration::from_millis(100)),
+      ..conn::Timeouts::default()
+    });
+    let mut conn = Framed::new(client, codec);
+    let store = |_: &MsgInfo| Ok((StoreType::Bytes, StoreType::Bytes));
+
+    // The payload stalls half-way through.
+    let mut rx = Receiver::new();
+    server
+      .write_all(b"Msg\nCmd 1\nLen 10\n\nhello")
+      .await
+      .unwrap();
+    match rx.recv(&mut conn, store).await {
+      Err(Error::Timeout(_)) => {}
+      _ => panic!("Expected timeout")
+    }
+    assert!(!conn.codec().is_poisoned() && rx.in_progress());
+
+    // The message is resumed once the rest of it arrives, and the next
+    // message is received intact.
+    server
+      .write_all(b"worldMsg\nCmd 2\nLen 2\n\nok")
+      .await
+      .unwrap();
+    let msg = rx.recv(&mut conn, store).await.unwrap();
+    assert_eq!(msg.cmd, 1);
+    assert_eq!(content(msg.payload).as_deref(), Some(&b"helloworld"[..]));
+    let msg = rx.recv(&mut conn, store).await.unwrap();
+    assert_eq!(msg.cmd, 2);
+    assert_eq!(content(msg.payload).as_deref(), Some(&b"ok"[..]));
+
+    // Without a receiver to resume the message, the connection can't be
+    // used after stalling half-way through.
+    server
+      .write_all(b"Msg\nCmd 3\nLen 10\n\nhello")
+      .await
+      .unwrap();
+    match recv(&mut conn, store).await {
+      Err(Error::Timeout(_)) => {}
+      _ => panic!("Expected timeout")
+    }
+    assert!(conn.codec().is_poisoned());
+  }
+
+  #[tokio::test]
+  async fn sub_rejected() {
+    let srv = MockServer::tcp().await.unwrap();
+    srv.inject("Sub", Fault::Fail("No such channel".into()));
+    srv.inject("Sub", Fault::Disconnect);
+
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    let si = SubInfo { ch: SubCh::Num(1) };
+    assert!(matches!(
+      subscribe(&mut conn, si.clone()).await,
+      Err(Error::ServerError(_))
+    ));
+    assert!(matches!(
+      subscribe(&mut conn, si).await,
+      Err(Error::Disconnected)
+    ));
+  }
+}
+
 // vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
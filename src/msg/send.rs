@@ -4,18 +4,16 @@ use std::borrow::Borrow;
 use std::fs;
 use std::path::PathBuf;
 
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 
 use tokio_util::codec::Framed;
 
-use futures::sink::SinkExt;
-
 use bytes::Bytes;
 
 use blather::{Params, Telegram};
 
 use crate::auth::Auth;
-use crate::conn::{self, ProtAddr};
+use crate::conn::{self, record::Direction, stats::Content, Codec, ProtAddr};
 use crate::types::AppChannel;
 
 use crate::err::Error;
@@ -33,7 +31,7 @@ impl InputType {
     match self {
       InputType::Params(params) => Ok(params.calc_buf_size()),
       InputType::File(f) => {
-        let metadata = fs::metadata(&f)?;
+        let metadata = fs::metadata(f)?;
         Ok(metadata.len() as usize)
       }
       InputType::VecBuf(v) => Ok(v.len()),
@@ -104,8 +102,9 @@ where
 /// Send a message, including (if applicable) its metadata and payload.
 ///
 /// On successful completion returns the transfer identifier.
+#[tracing::instrument(level = "debug", skip_all)]
 pub async fn send<T, X, M>(
-  conn: &mut Framed<T, blather::Codec>,
+  conn: &mut Framed<T, Codec>,
   xfer: X,
   mi: M
 ) -> Result<String, Error>
@@ -122,6 +121,13 @@ where
   //
   let metalen = mi.get_meta_size()?;
   let payloadlen = mi.get_payload_size()?;
+  tracing::debug!(
+    ch = %xfer.ch,
+    cmd = mi.cmd,
+    metalen,
+    payloadlen,
+    "sending message"
+  );
 
   //
   // Prepare the Msg telegram
@@ -154,42 +160,121 @@ where
     }
   };
 
+  tracing::debug!(xferid = %xferid, "transfer accepted");
+
   //
   // Transmit metadata, if applicable, and wait for the server to ACK it
   //
   if let Some(meta) = &mi.meta {
-    send_content(conn, meta).await?;
+    send_content(conn, meta, Content::Meta, metalen.into()).await?;
     crate::expect_okfail(conn).await?;
+    tracing::debug!(len = metalen, "metadata sent");
   }
 
   //
   // Transmit payload, if applicable, and wait for the server to ACK it
   //
   if let Some(payload) = &mi.payload {
-    send_content(conn, payload).await?;
+    send_content(conn, payload, Content::Payload, payloadlen).await?;
     crate::expect_okfail(conn).await?;
+    tracing::debug!(len = payloadlen, "payload sent");
   }
 
   Ok(xferid)
 }
 
 
+/// Transmit message content, and count its `len` bytes in the connection's
+/// statistics.
 async fn send_content<T>(
-  conn: &mut Framed<T, blather::Codec>,
-  data: &InputType
+  conn: &mut Framed<T, Codec>,
+  data: &InputType,
+  kind: Content,
+  len: u64
 ) -> Result<(), Error>
 where
   T: AsyncRead + AsyncWrite + Unpin
 {
   match data {
-    InputType::Params(params) => Ok(conn.send(params).await?),
+    InputType::Params(params) => conn::send_frame(conn, params).await?,
     InputType::File(fname) => {
       let mut f = tokio::fs::File::open(fname).await?;
-      let _ = tokio::io::copy(&mut f, conn.get_mut()).await?;
-      Ok(())
+      let mut buf = vec![0; 64 * 1024];
+      loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+          break;
+        }
+        conn::feed_frame(conn, &buf[..n]).await?;
+      }
+      conn::flush_frames(conn).await?;
+    }
+    InputType::VecBuf(v) => conn::send_frame(conn, v.as_slice()).await?,
+    InputType::Bytes(b) => conn::send_frame(conn, b.as_ref()).await?
+  }
+  conn.codec().stats().content(Direction::Send, kind, len);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::testing::{Fault, MockServer};
+
+  #[tokio::test]
+  async fn send_content() {
+    let srv = MockServer::tcp().await.unwrap();
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+
+    let mut meta = Params::new();
+    meta.add_str("Name", "hello").unwrap();
+    let xfer = Transport {
+      ch: AppChannel::Num(7)
+    };
+    let mi = MsgInfo {
+      cmd: 42,
+      meta: Some(InputType::Params(meta)),
+      payload: Some(InputType::VecBuf(b"payload".to_vec()))
+    };
+    send(&mut conn, &xfer, &mi).await.unwrap();
+
+    let mi = MsgInfo {
+      cmd: 0,
+      meta: None,
+      payload: Some(InputType::Bytes(Bytes::from_static(b"only")))
+    };
+    send(&mut conn, &xfer, &mi).await.unwrap();
+
+    let msgs = srv.take_received();
+    assert_eq!(msgs.len(), 2);
+    assert_eq!(msgs[0].ch, "7");
+    assert_eq!(msgs[0].cmd, 42);
+    assert_eq!(msgs[0].meta.as_deref(), Some(&b"Name hello\n\n"[..]));
+    assert_eq!(msgs[0].payload.as_deref(), Some(&b"payload"[..]));
+    assert!(msgs[1].meta.is_none());
+    assert_eq!(msgs[1].payload.as_deref(), Some(&b"only"[..]));
+  }
+
+  #[tokio::test]
+  async fn send_rejected() {
+    let srv = MockServer::tcp().await.unwrap();
+    srv.inject("Msg", Fault::Fail("Channel closed".into()));
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+
+    let xfer = Transport {
+      ch: AppChannel::Name("hello".into())
+    };
+    let mi = MsgInfo {
+      cmd: 1,
+      meta: None,
+      payload: None
+    };
+    match send(&mut conn, &xfer, &mi).await {
+      Err(Error::ServerError(_)) => {}
+      _ => panic!("Expected server error")
     }
-    InputType::VecBuf(v) => Ok(conn.send(v.as_slice()).await?),
-    InputType::Bytes(b) => Ok(conn.send(b.as_ref()).await?)
+    assert!(srv.take_received().is_empty());
   }
 }
 
@@ -0,0 +1,277 @@
+//! Supervised message reception.
+//!
+//! The [`run()`] function connects to a receiver node's subscription
+//! interface, subscribes to the application's message channel and keeps
+//! receiving messages.  If the subscription fails or the connection is lost
+//! it will keep retrying according to the `sub-retries` and
+//! `sub-retry-delay` settings in the configuration's `[receiver]` section.
+
+use std::future::Future;
+use std::time::Duration;
+
+use crate::conf::Config;
+use crate::conn::reconn::{self, Attempt, Backoff, Reconnector};
+use crate::err::Error;
+
+use super::recv::{self, Msg, MsgInfo, StoreType, SubInfo};
+
+
+/// Delay between subscription attempts if `sub-retry-delay` has not been
+/// configured.
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+
+/// Subscribe to the configured application message channel and keep
+/// processing incoming messages until `kill` is triggered.
+///
+/// The receiver node's `subif`, and the application channel, are taken from
+/// `conf`, and so are the authentication credentials if an `[auth]` section
+/// has been configured.
+///
+/// When a subscription attempt fails, or an established subscription is
+/// lost, a new attempt is made after `sub-retry-delay` (which defaults to ten
+/// seconds).  If `sub-retries` consecutive retries fail, the error of the
+/// last attempt is returned.  If `sub-retries` has not been set the
+/// supervisor will retry indefinitely.  The retry counter is reset each time
+/// a subscription has been successfully established.
+///
+/// Only connection errors, such as a lost connection, and the server
+/// rejecting the subscription request, are retried.  Other errors, such as
+/// invalid credentials or errors returned by `storeq` or `procmsg`, are
+/// returned immediately.
+///
+/// Returns `Ok(())` once `kill` has been triggered.
+pub async fn run<S, F, P>(
+  conf: &Config,
+  kill: killswitch::Shutdown,
+  mut storeq: S,
+  procmsg: P
+) -> Result<(), Error>
+where
+  S: FnMut(&MsgInfo) -> Result<(StoreType, StoreType), Error>,
+  F: Future<Output = Result<(), Error>>,
+  P: Fn(Msg) -> F
+{
+  let pa = conf
+    .get_receiver_subif()?
+    .ok_or_else(|| Error::miss_data("receiver subif"))?;
+  let ch = conf
+    .get_appch()?
+    .ok_or_else(|| Error::miss_data("application channel"))?;
+
+  let (retries, delay) = match &conf.receiver {
+    Some(r) => (r.sub_retries, r.get_sub_retry_delay()?),
+    None => (None, None)
+  };
+  let delay = delay.unwrap_or(DEFAULT_RETRY_DELAY);
+
+  // Backoff policy allowing `extra` attempts on top of the retries.
+  let backoff = |extra: u32| Backoff {
+    initial: delay,
+    max: delay,
+    factor: 1,
+    jitter: false,
+    max_attempts: retries.map(|n| n.saturating_add(extra))
+  };
+
+  let mut rc = Reconnector::new(pa);
+  rc.set_opts(conf.get_receiver_opts()?)
+    .set_auth(conf.auth.clone())
+    .set_sub(Some(SubInfo { ch: ch.into() }))
+    .set_retry_sub_fail(true)
+    .set_backoff(backoff(1))
+    .set_callback(|a| {
+      if let Attempt::Failure {
+        num,
+        err,
+        retry_in: Some(delay)
+      } = a
+      {
+        tracing::warn!(
+          "subscription attempt {} failed; {}; retrying in {:?}",
+          num,
+          err,
+          delay
+        );
+      }
+    });
+
+  loop {
+    let mut conn = tokio::select! {
+      conn = rc.connect() => conn?,
+      _ = kill.wait() => {
+        return Ok(());
+      }
+    };
+
+    match recv::recvloop_a(
+      &mut conn,
+      Some(kill.clone()),
+      &mut storeq,
+      &procmsg
+    )
+    .await
+    {
+      // The loop only returns Ok if it was terminated by the killswitch.
+      Ok(_) => return Ok(()),
+      Err(e) if reconn::is_transient(&e) && retries != Some(0) => {
+        tracing::warn!(
+          "subscription lost; {}; resubscribing in {:?}",
+          e,
+          delay
+        );
+        tokio::select! {
+          _ = tokio::time::sleep(delay) => { }
+          _ = kill.wait() => {
+            return Ok(());
+          }
+        }
+
+        // The attempt made after the delay is the first retry.
+        rc.set_backoff(backoff(0));
+      }
+      Err(e) => return Err(e)
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use bytes::Bytes;
+
+  use tokio::sync::mpsc;
+
+  use crate::conf::Receiver;
+  use crate::conn;
+  use crate::msg::recv::SubCh;
+  use crate::testing::{Fault, MockMsg, MockServer};
+
+  #[tokio::test]
+  async fn resubscribe() {
+    let srv = MockServer::tcp().await.unwrap();
+    let conf = Config {
+      channel: Some("7".into()),
+      receiver: Some(Receiver {
+        subif: Some(srv.protaddr().to_string()),
+        sub_retries: Some(2),
+        sub_retry_delay: Some("10 ms".into()),
+        ..Receiver::default()
+      }),
+      ..Config::default()
+    };
+    let msg = |cmd| MockMsg {
+      ch: "7".into(),
+      cmd,
+      meta: None,
+      payload: Some(Bytes::from_static(b"hello"))
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (_ks, kill) = killswitch::killswitch();
+    let store = |_: &MsgInfo| Ok((StoreType::None, StoreType::Bytes));
+    let sup = run(&conf, kill, store, |msg: Msg| {
+      let tx = tx.clone();
+      async move {
+        tx.send(msg.cmd).unwrap();
+        Ok(())
+      }
+    });
+
+    let script = async {
+      srv.push_msg(msg(1));
+      assert_eq!(rx.recv().await, Some(1));
+
+      // The lost subscription is reestablished.
+      srv.push_disconnect();
+      srv.push_msg(msg(2));
+      assert_eq!(rx.recv().await, Some(2));
+
+      // Losing the subscription again, and failing both retries, gives up.
+      for _ in 0..3 {
+        srv.inject("Sub", Fault::Disconnect);
+      }
+      srv.push_disconnect();
+    };
+
+    let (res, ()) = tokio::join!(sup, script);
+    assert!(matches!(res, Err(Error::Disconnected)), "{:?}", res);
+
+    // Only two retries were made, so one of the faults remains.
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    let res = recv::subscribe(&mut conn, SubInfo { ch: SubCh::Num(7) }).await;
+    assert!(res.is_err());
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    recv::subscribe(&mut conn, SubInfo { ch: SubCh::Num(7) })
+      .await
+      .unwrap();
+  }
+
+  #[tokio::test]
+  async fn rejected_sub() {
+    let srv = MockServer::tcp().await.unwrap();
+    let conf = Config {
+      channel: Some("7".into()),
+      receiver: Some(Receiver {
+        subif: Some(srv.protaddr().to_string()),
+        sub_retries: Some(1),
+        sub_retry_delay: Some("10 ms".into()),
+        ..Receiver::default()
+      }),
+      ..Config::default()
+    };
+    srv.inject("Sub", Fault::Fail("Not yet".into()));
+    srv.push_msg(MockMsg {
+      ch: "7".into(),
+      cmd: 1,
+      ..MockMsg::default()
+    });
+
+    // The rejected subscription is retried, and the retry succeeds.
+    let (ks, kill) = killswitch::killswitch();
+    let store = |_: &MsgInfo| Ok((StoreType::None, StoreType::None));
+    let res = run(&conf, kill, store, |msg: Msg| {
+      let ks = &ks;
+      async move {
+        assert_eq!(msg.cmd, 1);
+        ks.trigger();
+        Ok(())
+      }
+    })
+    .await;
+    assert!(res.is_ok(), "{:?}", res);
+  }
+
+  #[tokio::test]
+  async fn app_error() {
+    let srv = MockServer::tcp().await.unwrap();
+    let conf = Config {
+      channel: Some("7".into()),
+      receiver: Some(Receiver {
+        subif: Some(srv.protaddr().to_string()),
+        sub_retry_delay: Some("10 ms".into()),
+        ..Receiver::default()
+      }),
+      ..Config::default()
+    };
+    srv.push_msg(MockMsg {
+      ch: "7".into(),
+      cmd: 1,
+      ..MockMsg::default()
+    });
+
+    // Errors from the application are not retried, even without a retry
+    // limit.
+    let (_ks, kill) = killswitch::killswitch();
+    let store = |_: &MsgInfo| Ok((StoreType::None, StoreType::None));
+    let res = run(&conf, kill, store, |_| async {
+      Err(Error::BadInput("rejected".into()))
+    })
+    .await;
+    assert!(matches!(res, Err(Error::BadInput(_))), "{:?}", res);
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -6,6 +6,7 @@ use tokio_util::codec::Framed;
 
 use blather::Telegram;
 
+use crate::conn::Codec;
 use crate::err::Error;
 use crate::types;
 
@@ -43,7 +44,7 @@ fn parse<T: FromStr>(
 
 
 pub async fn get_nodeinfo<T: AsyncRead + AsyncWrite + Unpin>(
-  conn: &mut Framed<T, blather::Codec>
+  conn: &mut Framed<T, Codec>
 ) -> Result<NodeInfo, Error> {
   let mut tg = Telegram::new();
   tg.set_topic("GetNodeInfo")?;
@@ -0,0 +1,891 @@
+//! In-process mock DDMW core server, for integration testing.
+//!
+//! [`MockServer`] listens on a TCP/IP or unix local domain socket and speaks
+//! enough of the client interface protocol to exercise the functions in this
+//! crate without a live DDMW core.  It understands the following requests:
+//! - `Auth` (using either `AccName`/`Pass` or `Tkn`), `Unauth` and `WhoAmI`
+//! - `GetNodeInfo`
+//! - `Msg`, including metadata and payload content
+//! - `Sub`
+//! - `RdAcc`, `LsAcc`, `WrAcc` and `RmAcc`
+//!
+//! The server's accounts, node information and messages are all held in
+//! memory and can be inspected and modified by the test while the server is
+//! running.
+//!
+//! The mock is modelled on the requests this crate sends rather than on the
+//! server, so a passing test shows that requests and replies are built and
+//! parsed consistently, not that a DDMW core would accept them.  In
+//! particular, permissions are not enforced: every authenticated or
+//! unauthenticated connection may issue every request, so tests can not rely
+//! on the server refusing requests the connection's owner lacks permission
+//! for.
+//!
+//! Faults can be injected using [`MockServer::inject()`], which makes the
+//! server reply `Fail` to, drop the connection on, or never reply to, the
+//! next request with a given topic.
+//!
+//! This module is only available if the `testing` feature has been enabled.
+//!
+//! # Example
+//! ```
+//! use ddmw_client::{conn, testing::MockServer};
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let srv = MockServer::tcp().await.unwrap();
+//! let id = srv.add_account("alice", "secret", &["msg.send"]);
+//!
+//! let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+//! ddmw_client::auth::accpass(
+//!   &mut conn,
+//!   "alice",
+//!   ddmw_client::auth::CredStore::Buf("secret".into()),
+//!   false
+//! )
+//! .await
+//! .unwrap();
+//! assert_eq!(conn::whoami(&mut conn).await.unwrap().id, id);
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use futures::sink::SinkExt;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use tokio::sync::watch;
+
+use tokio_stream::StreamExt;
+
+use tokio_util::codec::Framed;
+
+use bytes::Bytes;
+
+use blather::{codec, Params, Telegram};
+
+use crate::conn::ProtAddr;
+use crate::err::Error;
+
+
+/// Identifier of the built-in unauthenticated account, which owns new
+/// connections.
+pub const UNAUTH_ID: i64 = 0;
+
+/// Name of the built-in unauthenticated account.
+pub const UNAUTH_NAME: &str = "unauthenticated";
+
+
+/// An account known to the mock server.
+#[derive(Clone, Debug)]
+pub struct MockAccount {
+  pub id: i64,
+  pub name: String,
+  pub username: Option<String>,
+  pub pass: String,
+  pub lock: bool,
+  pub perms: HashSet<String>
+}
+
+
+/// A message passing through the mock server.
+#[derive(Clone, Debug, Default)]
+pub struct MockMsg {
+  /// Application message channel.
+  pub ch: String,
+
+  /// Message command number.
+  pub cmd: u32,
+
+  /// Message metadata, if any.
+  pub meta: Option<Bytes>,
+
+  /// Message payload, if any.
+  pub payload: Option<Bytes>
+}
+
+
+/// A fault to inject into a request's processing.
+#[derive(Clone, Debug)]
+pub enum Fault {
+  /// Reply `Fail`, with the `String` as the error reason, instead of
+  /// processing the request.
+  Fail(String),
+
+  /// Close the connection without replying.
+  Disconnect,
+
+  /// Send an unsolicited telegram, and then process the request normally.
+  Notify(Telegram),
+
+  /// Never reply, and stop processing requests on the connection, but keep
+  /// it open until the client closes it.
+  Stall
+}
+
+
+/// Data queued for delivery to subscribed connections.
+enum Outbound {
+  Msg(MockMsg),
+  Notify(Telegram),
+  Disconnect
+}
+
+
+struct Inner {
+  accounts: BTreeMap<i64, MockAccount>,
+  next_id: i64,
+  tokens: HashMap<String, i64>,
+  next_tkn: u64,
+  nodeinfo: Params,
+  outbox: VecDeque<Outbound>,
+  inbox: Vec<MockMsg>,
+  next_xfer: u64,
+  faults: HashMap<String, VecDeque<Fault>>
+}
+
+struct Shared {
+  inner: Mutex<Inner>,
+
+  /// Signalled whenever a message is queued for delivery to subscribers.
+  outbox_tx: watch::Sender<()>
+}
+
+impl Shared {
+  fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+    self.inner.lock().unwrap()
+  }
+
+  fn take_fault(&self, topic: &str) -> Option<Fault> {
+    self.lock().faults.get_mut(topic)?.pop_front()
+  }
+
+  fn take_outbound(&self, ch: &str) -> Option<Outbound> {
+    let mut inner = self.lock();
+    let idx = inner.outbox.iter().position(|o| match o {
+      Outbound::Msg(m) => m.ch == ch,
+      Outbound::Notify(_) | Outbound::Disconnect => true
+    })?;
+    inner.outbox.remove(idx)
+  }
+}
+
+
+/// Per-connection state.
+struct Session {
+  owner: i64,
+  sub: Option<String>
+}
+
+
+/// A scriptable fake DDMW core server.
+///
+/// The server, and all its connections, are shut down when the `MockServer`
+/// is dropped.
+pub struct MockServer {
+  pa: ProtAddr,
+  shared: Arc<Shared>,
+  ks: killswitch::KillSwitch,
+  kill: killswitch::Shutdown
+}
+
+impl MockServer {
+  /// Start a mock server listening on an ephemeral TCP/IP port on the
+  /// loopback interface.
+  pub async fn tcp() -> Result<Self, Error> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let pa = ProtAddr::Tcp(listener.local_addr()?.to_string());
+    let srv = Self::new(pa);
+
+    let shared = Arc::clone(&srv.shared);
+    let kill = srv.kill.clone();
+    tokio::spawn(async move {
+      loop {
+        tokio::select! {
+          res = listener.accept() => match res {
+            Ok((sock, _)) => {
+              tokio::spawn(serve(Arc::clone(&shared), sock, kill.clone()));
+            }
+            Err(_) => break
+          },
+          _ = kill.wait() => break
+        }
+      }
+    });
+
+    Ok(srv)
+  }
+
+  /// Start a mock server listening on the unix local domain socket `path`.
+  /// A stale socket file at `path` is removed first.
+  #[cfg(unix)]
+  pub async fn uds<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
+    let path = path.into();
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    let srv = Self::new(ProtAddr::Uds(path));
+
+    let shared = Arc::clone(&srv.shared);
+    let kill = srv.kill.clone();
+    tokio::spawn(async move {
+      loop {
+        tokio::select! {
+          res = listener.accept() => match res {
+            Ok((sock, _)) => {
+              tokio::spawn(serve(Arc::clone(&shared), sock, kill.clone()));
+            }
+            Err(_) => break
+          },
+          _ = kill.wait() => break
+        }
+      }
+    });
+
+    Ok(srv)
+  }
+
+  fn new(pa: ProtAddr) -> Self {
+    let mut nodeinfo = Params::new();
+    for (k, v) in &[
+      ("ddmw.node", "sender"),
+      ("ddmw.version", "0.0.0-mock"),
+      ("os.name", std::env::consts::OS),
+      ("ddmw.ddlnk.engine", "mock"),
+      ("ddmw.ddlink.protocol", "udp"),
+      ("ddmw.ddlink.protimpl", "generic")
+    ] {
+      // Keys and values are known to be valid.
+      nodeinfo.add_str(k, v).unwrap();
+    }
+
+    let (outbox_tx, _) = watch::channel(());
+    let shared = Shared {
+      inner: Mutex::new(Inner {
+        accounts: BTreeMap::new(),
+        next_id: UNAUTH_ID + 1,
+        tokens: HashMap::new(),
+        next_tkn: 1,
+        nodeinfo,
+        outbox: VecDeque::new(),
+        inbox: Vec::new(),
+        next_xfer: 1,
+        faults: HashMap::new()
+      }),
+      outbox_tx
+    };
+
+    let (ks, kill) = killswitch::killswitch();
+    MockServer {
+      pa,
+      shared: Arc::new(shared),
+      ks,
+      kill
+    }
+  }
+
+  /// Return the address clients should connect to.
+  pub fn protaddr(&self) -> ProtAddr {
+    self.pa.clone()
+  }
+
+  /// Add an account, and return its identifier.
+  pub fn add_account(&self, name: &str, pass: &str, perms: &[&str]) -> i64 {
+    let mut inner = self.shared.lock();
+    let id = inner.next_id;
+    inner.next_id += 1;
+    inner.accounts.insert(
+      id,
+      MockAccount {
+        id,
+        name: name.to_string(),
+        username: None,
+        pass: pass.to_string(),
+        lock: false,
+        perms: perms.iter().map(|p| p.to_string()).collect()
+      }
+    );
+    id
+  }
+
+  /// Return a copy of the account named `name`, if it exists.
+  pub fn account(&self, name: &str) -> Option<MockAccount> {
+    let inner = self.shared.lock();
+    inner.accounts.values().find(|a| a.name == name).cloned()
+  }
+
+  /// Modify the account named `name`.  Returns `false` if there's no such
+  /// account.
+  pub fn modify_account<F>(&self, name: &str, f: F) -> bool
+  where
+    F: FnOnce(&mut MockAccount)
+  {
+    let mut inner = self.shared.lock();
+    match inner.accounts.values_mut().find(|a| a.name == name) {
+      Some(acc) => {
+        f(acc);
+        true
+      }
+      None => false
+    }
+  }
+
+  /// Issue an authentication token for the account `id`.
+  pub fn issue_token(&self, id: i64) -> String {
+    issue_token(&mut self.shared.lock(), id)
+  }
+
+  /// Revoke an authentication token.
+  pub fn revoke_token(&self, tkn: &str) {
+    self.shared.lock().tokens.remove(tkn);
+  }
+
+  /// Override a parameter in the `GetNodeInfo` reply.
+  pub fn set_nodeinfo(&self, key: &str, value: &str) -> Result<(), Error> {
+    self.shared.lock().nodeinfo.add_str(key, value)?;
+    Ok(())
+  }
+
+  /// Queue a message for delivery to a connection subscribed to `msg.ch`.
+  /// Messages are kept until a subscriber appears.
+  pub fn push_msg(&self, msg: MockMsg) {
+    self.shared.lock().outbox.push_back(Outbound::Msg(msg));
+    self.shared.outbox_tx.send_replace(());
+  }
+
+  /// Queue an unsolicited telegram for delivery to a subscribed connection.
+  /// It is delivered in order with the messages queued by
+  /// [`push_msg()`](Self::push_msg).
+  pub fn push_notification(&self, tg: Telegram) {
+    self.shared.lock().outbox.push_back(Outbound::Notify(tg));
+    self.shared.outbox_tx.send_replace(());
+  }
+
+  /// Queue a disconnect for a subscribed connection.  The connection which
+  /// picks it up, in order with the messages queued by
+  /// [`push_msg()`](Self::push_msg), is closed.
+  pub fn push_disconnect(&self) {
+    self.shared.lock().outbox.push_back(Outbound::Disconnect);
+    self.shared.outbox_tx.send_replace(());
+  }
+
+  /// Return, and forget, the messages that clients have sent to the server.
+  pub fn take_received(&self) -> Vec<MockMsg> {
+    std::mem::take(&mut self.shared.lock().inbox)
+  }
+
+  /// Inject a fault for the next request with the topic `topic`.  Faults for
+  /// the same topic are applied in the order they were injected, one per
+  /// request.
+  pub fn inject(&self, topic: &str, fault: Fault) {
+    self
+      .shared
+      .lock()
+      .faults
+      .entry(topic.to_string())
+      .or_default()
+      .push_back(fault);
+  }
+}
+
+impl Drop for MockServer {
+  fn drop(&mut self) {
+    self.ks.trigger();
+
+    #[cfg(unix)]
+    if let ProtAddr::Uds(path) = &self.pa {
+      let _ = std::fs::remove_file(path);
+    }
+  }
+}
+
+
+fn issue_token(inner: &mut Inner, id: i64) -> String {
+  let tkn = format!("mocktkn-{}-{}", id, inner.next_tkn);
+  inner.next_tkn += 1;
+  inner.tokens.insert(tkn.clone(), id);
+  tkn
+}
+
+
+/// Process a single client connection until it is closed, a `Disconnect`
+/// fault is triggered, or the server is shut down.
+async fn serve<T>(shared: Arc<Shared>, sock: T, kill: killswitch::Shutdown)
+where
+  T: AsyncRead + AsyncWrite + Unpin
+{
+  let mut conn = Framed::new(sock, blather::Codec::new());
+  let mut outbox = shared.outbox_tx.subscribe();
+  let mut sess = Session {
+    owner: UNAUTH_ID,
+    sub: None
+  };
+
+  loop {
+    if let Some(ch) = &sess.sub {
+      outbox.borrow_and_update();
+      while let Some(out) = shared.take_outbound(ch) {
+        let res = match out {
+          Outbound::Msg(msg) => deliver(&mut conn, msg).await,
+          Outbound::Notify(tg) => conn.send(&tg).await.map_err(Error::from),
+          Outbound::Disconnect => return
+        };
+        if res.is_err() {
+          return;
+        }
+      }
+    }
+
+    let frame = tokio::select! {
+      frame = conn.next() => frame,
+      res = outbox.changed(), if sess.sub.is_some() => {
+        if res.is_err() {
+          return;
+        }
+        continue;
+      }
+      _ = kill.wait() => return
+    };
+    let tg = match frame {
+      Some(Ok(codec::Input::Telegram(tg))) => tg,
+      _ => return
+    };
+    let topic = tg.get_topic().unwrap_or_default().to_string();
+    let params = tg.into_params();
+
+    let res = match shared.take_fault(&topic) {
+      Some(Fault::Disconnect) => return,
+      Some(Fault::Stall) => {
+        tokio::select! {
+          _ = async { while let Some(Ok(_)) = conn.next().await {} } => {}
+          _ = kill.wait() => {}
+        }
+        return;
+      }
+      Some(Fault::Fail(reason)) => reply(&mut conn, Err(reason)).await,
+      Some(Fault::Notify(tg)) => match conn.send(&tg).await {
+        Ok(_) => process(&shared, &mut conn, &mut sess, &topic, &params).await,
+        Err(e) => Err(e.into())
+      },
+      None => process(&shared, &mut conn, &mut sess, &topic, &params).await
+    };
+    if res.is_err() {
+      return;
+    }
+  }
+}
+
+
+/// Process a request and reply to it.
+async fn process<T>(
+  shared: &Shared,
+  conn: &mut Framed<T, blather::Codec>,
+  sess: &mut Session,
+  topic: &str,
+  params: &Params
+) -> Result<(), Error>
+where
+  T: AsyncRead + AsyncWrite + Unpin
+{
+  if topic == "Msg" {
+    return recv_msg(shared, conn, params).await;
+  }
+  let res = handle(&mut shared.lock(), sess, topic, params);
+  reply(conn, res).await
+}
+
+
+/// Send an `Ok` reply with the parameters in `res`, or a `Fail` reply with
+/// the error reason in `res`.
+async fn reply<T>(
+  conn: &mut Framed<T, blather::Codec>,
+  res: Result<Params, String>
+) -> Result<(), Error>
+where
+  T: AsyncRead + AsyncWrite + Unpin
+{
+  let tg = match res {
+    Ok(params) => {
+      let mut tg = Telegram::from(params);
+      tg.set_topic("Ok")?;
+      tg
+    }
+    Err(reason) => {
+      let mut tg = Telegram::new_topic("Fail")?;
+      tg.add_param("Err", reason)?;
+      tg
+    }
+  };
+  conn.send(&tg).await?;
+  Ok(())
+}
+
+
+/// Receive a message, including its metadata and payload, from a client.
+async fn recv_msg<T>(
+  shared: &Shared,
+  conn: &mut Framed<T, blather::Codec>,
+  params: &Params
+) -> Result<(), Error>
+where
+  T: AsyncRead + AsyncWrite + Unpin
+{
+  let ch = match params.get_str("_Ch") {
+    Some(ch) => ch.to_string(),
+    None => return reply(conn, Err("Missing channel".to_string())).await
+  };
+  let cmd = params.get_param_def::<u32>("Cmd", 0)?;
+  let metalen = params.get_param_def::<usize>("MetaLen", 0)?;
+  let payloadlen = params.get_param_def::<usize>("Len", 0)?;
+
+  let xferid = {
+    let mut inner = shared.lock();
+    let xferid = inner.next_xfer;
+    inner.next_xfer += 1;
+    xferid
+  };
+  let mut ok = Params::new();
+  ok.add_param("XferId", xferid)?;
+  reply(conn, Ok(ok)).await?;
+
+  let meta = recv_content(conn, metalen).await?;
+  let payload = recv_content(conn, payloadlen).await?;
+
+  shared.lock().inbox.push(MockMsg {
+    ch,
+    cmd,
+    meta,
+    payload
+  });
+
+  Ok(())
+}
+
+
+/// Receive a message content buffer of `len` bytes, and acknowledge it.
+async fn recv_content<T>(
+  conn: &mut Framed<T, blather::Codec>,
+  len: usize
+) -> Result<Option<Bytes>, Error>
+where
+  T: AsyncRead + AsyncWrite + Unpin
+{
+  if len == 0 {
+    return Ok(None);
+  }
+  conn.codec_mut().expect_bytes(len)?;
+  match conn.next().await {
+    Some(Ok(codec::Input::Bytes(buf))) => {
+      reply(conn, Ok(Params::new())).await?;
+      Ok(Some(buf))
+    }
+    Some(Err(e)) => Err(e.into()),
+    _ => Err(Error::Disconnected)
+  }
+}
+
+
+/// Deliver a message to a subscribed client.
+async fn deliver<T>(
+  conn: &mut Framed<T, blather::Codec>,
+  msg: MockMsg
+) -> Result<(), Error>
+where
+  T: AsyncRead + AsyncWrite + Unpin
+{
+  let mut tg = Telegram::new_topic("Msg")?;
+  tg.add_param("Ch", &msg.ch)?;
+  if msg.cmd != 0 {
+    tg.add_param("Cmd", msg.cmd)?;
+  }
+  if let Some(meta) = &msg.meta {
+    tg.add_param("MetaLen", meta.len())?;
+  }
+  if let Some(payload) = &msg.payload {
+    tg.add_param("Len", payload.len())?;
+  }
+  conn.send(&tg).await?;
+
+  if let Some(meta) = msg.meta {
+    conn.send(meta).await?;
+  }
+  if let Some(payload) = msg.payload {
+    conn.send(payload).await?;
+  }
+  Ok(())
+}
+
+
+/// Process a request which consists of a single telegram and its reply.
+fn handle(
+  inner: &mut Inner,
+  sess: &mut Session,
+  topic: &str,
+  params: &Params
+) -> Result<Params, String> {
+  let mut out = Params::new();
+  match topic {
+    "Auth" => {
+      let id = if let Some(tkn) = params.get_str("Tkn") {
+        *inner.tokens.get(tkn).ok_or("Invalid token")?
+      } else {
+        let name = params.get_str("AccName").ok_or("Missing AccName")?;
+        let pass = params.get_str("Pass").ok_or("Missing Pass")?;
+        match inner.accounts.values().find(|a| a.name == name) {
+          Some(acc) if acc.pass == pass && !acc.lock => acc.id,
+          _ => return Err("Invalid credentials".to_string())
+        }
+      };
+      if params.get_bool_def("ReqTkn", false).map_err(str_err)? {
+        out
+          .add_param("Tkn", issue_token(inner, id))
+          .map_err(str_err)?;
+      }
+      sess.owner = id;
+    }
+    "Unauth" => {
+      sess.owner = UNAUTH_ID;
+    }
+    "WhoAmI" => {
+      let name = if sess.owner == UNAUTH_ID {
+        UNAUTH_NAME.to_string()
+      } else {
+        match inner.accounts.get(&sess.owner) {
+          Some(acc) => acc.name.clone(),
+          None => return Err("Account not found".to_string())
+        }
+      };
+      out.add_param("Id", sess.owner).map_err(str_err)?;
+      out.add_param("Name", name).map_err(str_err)?;
+    }
+    "GetNodeInfo" => {
+      out = inner.nodeinfo.clone();
+    }
+    "Sub" => {
+      let ch = params.get_str("Ch").ok_or("Missing Ch")?;
+      sess.sub = Some(ch.to_string());
+    }
+    "RdAcc" => {
+      let id = if params.have("Id") || params.have("Name") {
+        find_account(inner, params)?
+      } else {
+        sess.owner
+      };
+      let acc = inner.accounts.get(&id).ok_or("Account not found")?;
+      out.add_param("Id", acc.id).map_err(str_err)?;
+      out.add_str("Name", &acc.name).map_err(str_err)?;
+      out.add_bool("Lock", acc.lock).map_err(str_err)?;
+      out.add_strit("Perms", acc.perms.iter()).map_err(str_err)?;
+    }
+    "LsAcc" => {
+      let all = params.get_bool_def("All", false).map_err(str_err)?;
+      let mut n = 0;
+      for acc in inner.accounts.values().filter(|a| all || !a.lock) {
+        out
+          .add_param(format!("{}.Id", n), acc.id)
+          .map_err(str_err)?;
+        out
+          .add_str(&format!("{}.Name", n), &acc.name)
+          .map_err(str_err)?;
+        n += 1;
+      }
+      out.add_param("#", n).map_err(str_err)?;
+    }
+    "WrAcc" => {
+      let id = find_account(inner, params)?;
+      let acc = inner.accounts.get_mut(&id).ok_or("Account not found")?;
+      if let Some(name) = params.get_str("NewName") {
+        acc.name = name.to_string();
+      }
+      if let Some(username) = params.get_str("UserName") {
+        acc.username = Some(username.to_string());
+      }
+      if params.have("Lock") {
+        acc.lock = params.get_bool("Lock").map_err(str_err)?;
+      }
+      if params.have("Perms") {
+        acc.perms = params.get_hashset("Perms").map_err(str_err)?;
+      }
+      for p in params.get_hashset("Grant").map_err(str_err)? {
+        acc.perms.insert(p);
+      }
+      for p in params.get_hashset("Revoke").map_err(str_err)? {
+        acc.perms.remove(&p);
+      }
+    }
+    "RmAcc" => {
+      let id = find_account(inner, params)?;
+      inner.accounts.remove(&id);
+      inner.tokens.retain(|_, owner| *owner != id);
+    }
+    _ => return Err(format!("Unknown request '{}'", topic))
+  }
+  Ok(out)
+}
+
+
+/// Look up the account referenced by an `Id` or `Name` parameter.
+fn find_account(inner: &Inner, params: &Params) -> Result<i64, String> {
+  if params.have("Id") {
+    let id = params.get_int::<i64>("Id").map_err(str_err)?;
+    if inner.accounts.contains_key(&id) {
+      return Ok(id);
+    }
+  } else if let Some(name) = params.get_str("Name") {
+    if let Some(acc) = inner.accounts.values().find(|a| a.name == name) {
+      return Ok(acc.id);
+    }
+  } else {
+    return Err("Missing account reference".to_string());
+  }
+  Err("Account not found".to_string())
+}
+
+
+fn str_err<E: ToString>(e: E) -> String {
+  e.to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::auth::{self, Auth, CredStore};
+  use crate::conn;
+  use crate::mgmt::acc::{self, ModPerms, WrAccount};
+  use crate::probe;
+  use crate::types::{node, ObjRef};
+
+  #[tokio::test]
+  async fn authenticate() {
+    let srv = MockServer::tcp().await.unwrap();
+    let id = srv.add_account("alice", "secret", &[]);
+
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    assert_eq!(conn::whoami(&mut conn).await.unwrap().id, UNAUTH_ID);
+
+    let tkn =
+      auth::accpass(&mut conn, "alice", CredStore::Buf("secret".into()), true)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(conn::whoami(&mut conn).await.unwrap().id, id);
+
+    auth::unauthenticate(&mut conn).await.unwrap();
+    assert_eq!(conn::whoami(&mut conn).await.unwrap().id, UNAUTH_ID);
+
+    let bad =
+      auth::accpass(&mut conn, "alice", CredStore::Buf("nope".into()), false);
+    assert!(matches!(bad.await, Err(Error::ServerError(_))));
+
+    let auth = Auth {
+      token: Some(tkn),
+      ..Auth::default()
+    };
+    let mut conn = conn::connect(srv.protaddr(), Some(&auth)).await.unwrap();
+    assert_eq!(conn::whoami(&mut conn).await.unwrap().name, "alice");
+  }
+
+  #[tokio::test]
+  async fn nodeinfo() {
+    let srv = MockServer::tcp().await.unwrap();
+    srv.set_nodeinfo("ddmw.node", "receiver").unwrap();
+
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    let ni = probe::get_nodeinfo(&mut conn).await.unwrap();
+    assert_eq!(ni.nodetype, node::Type::Receiver);
+  }
+
+  #[tokio::test]
+  async fn accounts() {
+    let srv = MockServer::tcp().await.unwrap();
+    let alice = srv.add_account("alice", "secret", &["a", "b"]);
+    srv.add_account("bob", "secret", &[]);
+
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    let wr = WrAccount {
+      name: None,
+      username: Some("Alice".into()),
+      lock: Some(true),
+      perms: Some(ModPerms::GrantRevoke(
+        ["c".to_string()].iter().cloned().collect(),
+        ["a".to_string()].iter().cloned().collect()
+      ))
+    };
+    acc::wr(&mut conn, ObjRef::Id(alice), wr).await.unwrap();
+
+    let acc = acc::rd(&mut conn, Some(ObjRef::Name("alice".into())))
+      .await
+      .unwrap();
+    assert!(acc.lock);
+    assert!(acc.perms.contains("b") && acc.perms.contains("c"));
+    assert!(!acc.perms.contains("a"));
+    assert_eq!(srv.account("alice").unwrap().username.unwrap(), "Alice");
+
+    assert_eq!(acc::ls(&mut conn, false).await.unwrap().len(), 1);
+    assert_eq!(acc::ls(&mut conn, true).await.unwrap().len(), 2);
+
+    let refs = vec![
+      ObjRef::Name("bob".into()),
+      ObjRef::Id(99),
+      ObjRef::Id(alice),
+    ];
+    let accs = acc::rd_many(&mut conn, refs).await.unwrap();
+    assert_eq!(accs[0].as_ref().unwrap().name, "bob");
+    assert!(accs[1].is_err());
+    assert_eq!(accs[2].as_ref().unwrap().name, "alice");
+
+    acc::rm(&mut conn, ObjRef::Name("bob".into()))
+      .await
+      .unwrap();
+    assert!(srv.account("bob").is_none());
+    assert!(acc::rm(&mut conn, ObjRef::Name("bob".into()))
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn faults() {
+    let srv = MockServer::tcp().await.unwrap();
+    srv.inject("WhoAmI", Fault::Fail("Nope".into()));
+    srv.inject("WhoAmI", Fault::Disconnect);
+
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    match conn::whoami(&mut conn).await {
+      Err(Error::ServerError(params)) => {
+        assert_eq!(params.get_str("Err"), Some("Nope"))
+      }
+      _ => panic!("Expected server error")
+    }
+    assert!(matches!(
+      conn::whoami(&mut conn).await,
+      Err(Error::Disconnected)
+    ));
+
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    assert!(conn::whoami(&mut conn).await.is_ok());
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn uds() {
+    let path = std::env::temp_dir()
+      .join(format!("ddmw-mock-{}.sock", std::process::id()));
+    let srv = MockServer::uds(&path).await.unwrap();
+    let mut conn = conn::connect(srv.protaddr(), None).await.unwrap();
+    assert!(conn::whoami(&mut conn).await.is_ok());
+    drop(srv);
+    assert!(!path.exists());
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
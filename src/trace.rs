@@ -0,0 +1,66 @@
+//! Helpers for logging protocol traffic.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use blather::Telegram;
+
+
+/// Parameters whose values must never end up in logs.
+pub(crate) const SECRET_KEYS: &[&str] = &["Pass", "Tkn"];
+
+
+/// Formats a telegram's topic and parameters for logging, with the values of
+/// credential parameters replaced by a placeholder.  Parameters are sorted by
+/// key.
+pub(crate) struct Redacted<'a> {
+  topic: Option<&'a str>,
+  params: &'a HashMap<String, String>
+}
+
+impl fmt::Display for Redacted<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.topic.unwrap_or("-"))?;
+
+    let mut keys: Vec<&String> = self.params.keys().collect();
+    keys.sort();
+    for key in keys {
+      if SECRET_KEYS.contains(&key.as_str()) {
+        write!(f, " {}=<redacted>", key)?;
+      } else {
+        write!(f, " {}={}", key, self.params[key])?;
+      }
+    }
+    Ok(())
+  }
+}
+
+
+/// Return a loggable, redacted, representation of a telegram.
+pub(crate) fn tg(tg: &Telegram) -> Redacted<'_> {
+  Redacted {
+    topic: tg.get_topic(),
+    params: tg.get_params_inner()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn redact_secrets() {
+    let mut t = Telegram::new_topic("Auth").unwrap();
+    t.add_param("AccName", "alice").unwrap();
+    t.add_param("Pass", "secret").unwrap();
+    t.add_param("Tkn", "abc123").unwrap();
+    let s = tg(&t).to_string();
+    assert_eq!(s, "Auth AccName=alice Pass=<redacted> Tkn=<redacted>");
+
+    let t = Telegram::new_topic("Ok").unwrap();
+    assert_eq!(tg(&t).to_string(), "Ok");
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
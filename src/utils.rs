@@ -1,44 +1,223 @@
-use std::fs::File;
-use std::io::BufRead;
-use std::path::Path;
-
-/// Read the first line of a file.
-/// Returns `None` on any problems (file not found, unable to open, file has
-/// no lines, etc).
-/// Returns `Some(String)` on success.
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use zeroize::Zeroizing;
+
+use crate::err::Error;
+
+/// Return `fname` with `ext` appended to it.
+fn with_suffix(fname: &Path, ext: &str) -> PathBuf {
+  let mut s = OsString::from(fname.as_os_str());
+  s.push(ext);
+  PathBuf::from(s)
+}
+
+
+/// Open a file for writing, creating it readable and writable only by its
+/// owner if it does not exist.  If `excl` is set the file must not exist.
+pub(crate) fn create_private(
+  fname: &Path,
+  excl: bool
+) -> std::io::Result<File> {
+  let mut opts = OpenOptions::new();
+  opts.write(true);
+  if excl {
+    opts.create_new(true);
+  } else {
+    opts.create(true);
+  }
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+    opts.mode(0o600);
+  }
+  opts.open(fname)
+}
+
+
+/// Atomically replace the contents of a file which holds a secret.
+///
+/// The data is written to a temporary file, readable only by its owner, in
+/// the same directory, which is synced and then renamed over `fname`.  An
+/// exclusive advisory lock is held on `<fname>.lock` meanwhile, so that
+/// processes sharing the file serialize their updates.
 ///
-/// # Notes
-/// - This file will trim the end of the string, and thus it is unsuitable for
-///   reading strings which may end with whitespaces.
-pub(crate) fn read_single_line<P>(fname: P) -> Option<String>
+/// The file operations block, so they are run on tokio's blocking thread
+/// pool.
+pub(crate) async fn write_secret_file<P>(
+  fname: P,
+  data: &[u8]
+) -> std::io::Result<()>
 where
   P: AsRef<Path>
 {
-  if let Ok(mut lines) = read_lines(fname.as_ref()) {
-    if let Some(line) = lines.next() {
-      if let Ok(l) = line {
-        Some(l.trim_end().to_string())
-      } else {
-        None
-      }
+  let fname = fname.as_ref().to_path_buf();
+  let data = Zeroizing::new(data.to_vec());
+  tokio::task::spawn_blocking(move || {
+    write_secret_file_blocking(&fname, &data)
+  })
+  .await
+  .map_err(std::io::Error::other)?
+}
+
+
+fn write_secret_file_blocking(
+  fname: &Path,
+  data: &[u8]
+) -> std::io::Result<()> {
+  let lock = create_private(&with_suffix(fname, ".lock"), false)?;
+  lock_exclusive(&lock)?;
+
+  // Only the lock holder writes the temporary file, so if it exists it was
+  // left behind by a writer which was interrupted.
+  let tmpname = with_suffix(fname, ".tmp");
+  match fs::remove_file(&tmpname) {
+    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+    _ => {}
+  }
+
+  let res = (|| {
+    let mut f = create_private(&tmpname, true)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    fs::rename(&tmpname, fname)
+  })();
+  if res.is_err() {
+    let _ = fs::remove_file(&tmpname);
+    return res;
+  }
+
+  // Make the rename itself durable.
+  #[cfg(unix)]
+  if let Some(dir) = fname.parent() {
+    let dir = if dir.as_os_str().is_empty() {
+      Path::new(".")
     } else {
-      None
+      dir
+    };
+    File::open(dir)?.sync_all()?;
+  }
+
+  // The lock is released when `lock` is dropped.
+  Ok(())
+}
+
+
+/// Take an exclusive advisory lock on a file.  The lock is released when the
+/// file is closed.
+#[cfg(unix)]
+fn lock_exclusive(f: &File) -> std::io::Result<()> {
+  use std::os::unix::io::AsRawFd;
+
+  loop {
+    // SAFETY: flock() does not access any memory, and `f` keeps the
+    // descriptor open for the duration of the call.
+    if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX) } == 0 {
+      return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    if err.kind() != std::io::ErrorKind::Interrupted {
+      return Err(err);
     }
-  } else {
-    None
   }
 }
 
+/// Advisory locks are only taken on unix-like platforms.
+#[cfg(not(unix))]
+fn lock_exclusive(_f: &File) -> std::io::Result<()> {
+  Ok(())
+}
 
-/// Return a buffered line iterator for reading a file line-by-line.
-pub(crate) fn read_lines<P>(
-  filename: P
-) -> std::io::Result<std::io::Lines<std::io::BufReader<File>>>
-where
-  P: AsRef<Path>
-{
-  let file = std::fs::File::open(filename)?;
-  Ok(std::io::BufReader::new(file).lines())
+
+/// Parse a human readable duration, such as `"1 minute"`, `"30s"` or
+/// `"1.5 hours"`.
+///
+/// A number without a unit is interpreted as seconds.
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, Error> {
+  let s = s.trim();
+  let idx = s
+    .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+    .unwrap_or(s.len());
+  let (num, unit) = s.split_at(idx);
+
+  let num = num
+    .parse::<f64>()
+    .map_err(|_| Error::parse(format!("Invalid duration '{}'", s)))?;
+
+  let secs = match unit.trim() {
+    "ms" | "msec" | "msecs" | "millisecond" | "milliseconds" => 0.001,
+    "" | "s" | "sec" | "secs" | "second" | "seconds" => 1.0,
+    "m" | "min" | "mins" | "minute" | "minutes" => 60.0,
+    "h" | "hour" | "hours" => 60.0 * 60.0,
+    "d" | "day" | "days" => 24.0 * 60.0 * 60.0,
+    u => {
+      return Err(Error::parse(format!("Unknown duration unit '{}'", u)));
+    }
+  };
+
+  Duration::try_from_secs_f64(num * secs)
+    .map_err(|_| Error::parse(format!("Duration '{}' out of range", s)))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn durations() {
+    assert_eq!(parse_duration("1 minute").unwrap(), Duration::from_secs(60));
+    assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+    assert_eq!(parse_duration("15").unwrap(), Duration::from_secs(15));
+    assert_eq!(
+      parse_duration("250 ms").unwrap(),
+      Duration::from_millis(250)
+    );
+    assert_eq!(
+      parse_duration("1.5 hours").unwrap(),
+      Duration::from_secs(90 * 60)
+    );
+  }
+
+  #[test]
+  fn bad_durations() {
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("minute").is_err());
+    assert!(parse_duration("1 fortnight").is_err());
+    assert!(parse_duration("inf").is_err());
+    assert!(parse_duration("1e400").is_err());
+    assert!(parse_duration("99999999999999999999 days").is_err());
+    assert!(parse_duration(&format!("1{}", "0".repeat(400))).is_err());
+  }
+
+  #[tokio::test]
+  async fn secret_file() {
+    let fname = std::env::temp_dir()
+      .join(format!("ddmw-secret-{}.txt", std::process::id()));
+    fs::write(&fname, "old").unwrap();
+
+    write_secret_file(&fname, b"new").await.unwrap();
+    assert_eq!(fs::read_to_string(&fname).unwrap(), "new");
+
+    // A temporary file left behind by an interrupted writer is replaced.
+    fs::write(with_suffix(&fname, ".tmp"), "stale").unwrap();
+    write_secret_file(&fname, b"newer").await.unwrap();
+    assert_eq!(fs::read_to_string(&fname).unwrap(), "newer");
+    assert!(!with_suffix(&fname, ".tmp").exists());
+
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = fs::metadata(&fname).unwrap().permissions().mode();
+      assert_eq!(mode & 0o777, 0o600);
+    }
+
+    let _ = fs::remove_file(&fname);
+    let _ = fs::remove_file(with_suffix(&fname, ".lock"));
+  }
 }
 
 // vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :